@@ -1,33 +1,248 @@
+use std::str::FromStr;
+
 use once_cell::sync::Lazy;
 
 fn get_env(env: &'static str) -> String {
     std::env::var(env).unwrap_or_else(|_| panic!("Cannot get the {} env variable", env))
 }
 
+fn get_env_or<T: FromStr>(env: &'static str, default: T) -> T {
+    match std::env::var(env) {
+        Ok(v) => v
+            .parse()
+            .unwrap_or_else(|_| panic!("Cannot parse the {} env variable", env)),
+        Err(_) => default,
+    }
+}
+
+/// Where completed archives are kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageBackend {
+    Local,
+    S3,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(StorageBackend::Local),
+            "s3" => Ok(StorageBackend::S3),
+            _ => Err(format!("unknown storage backend: {s}")),
+        }
+    }
+}
+
+/// How `/api/download/` serves archives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DownloadMode {
+    /// Stream the archive through this service.
+    Proxy,
+    /// Redirect to a presigned URL if the storage backend supports it.
+    Redirect,
+}
+
+impl FromStr for DownloadMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "proxy" => Ok(DownloadMode::Proxy),
+            "redirect" => Ok(DownloadMode::Redirect),
+            _ => Err(format!("unknown download mode: {s}")),
+        }
+    }
+}
+
+/// Roles of this instance.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunMode {
+    /// Serve the API and build archives.
+    All,
+    /// Only accept tasks and report their state.
+    Api,
+    /// Only build archives of the queued tasks.
+    Worker,
+}
+
+impl RunMode {
+    pub fn serves_api(self) -> bool {
+        self != RunMode::Worker
+    }
+
+    pub fn builds_archives(self) -> bool {
+        self != RunMode::Api
+    }
+}
+
+impl FromStr for RunMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(RunMode::All),
+            "api" => Ok(RunMode::Api),
+            "worker" => Ok(RunMode::Worker),
+            _ => Err(format!("unknown run mode: {s}")),
+        }
+    }
+}
+
+/// Where task state is kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TaskStoreBackend {
+    /// Process-local, for a single instance.
+    Memory,
+    /// Shared by all instances.
+    Redis,
+}
+
+impl FromStr for TaskStoreBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(TaskStoreBackend::Memory),
+            "redis" => Ok(TaskStoreBackend::Redis),
+            _ => Err(format!("unknown task store backend: {s}")),
+        }
+    }
+}
+
 pub struct Config {
+    pub run_mode: RunMode,
+    pub worker_concurrency: usize,
+    pub shutdown_grace_period_secs: u64,
+
+    pub ready_check_timeout_secs: u64,
+    pub ready_max_queue_depth: usize,
+
     pub api_key: String,
+    pub admin_api_key: String,
+    pub task_secret: String,
 
     pub library_api_key: String,
     pub library_url: String,
+    pub library_timeout_secs: u64,
+    pub library_max_retries: u32,
+    pub library_circuit_threshold: u32,
+    pub library_circuit_cooldown_secs: u64,
+    pub library_page_size: u32,
+    pub library_page_concurrency: usize,
+    pub library_cache_ttl_secs: u64,
 
     pub cache_api_key: String,
     pub cache_url: String,
+    pub cache_connect_timeout_secs: u64,
+    pub cache_read_timeout_secs: u64,
+    pub cache_timeout_secs: u64,
+
+    pub download_stall_window_secs: u64,
+    pub download_min_throughput: u64,
+    pub task_timeout_secs: u64,
+    pub task_stall_timeout_secs: u64,
 
     pub sentry_dsn: String,
+
+    pub storage_dir: String,
+    pub instance_id: String,
+    pub storage_backend: StorageBackend,
+    pub download_mode: DownloadMode,
+    pub presigned_url_ttl_secs: u32,
+
+    pub task_store_backend: TaskStoreBackend,
+    pub redis_url: String,
+    pub redis_key_prefix: String,
+
+    pub s3_bucket: String,
+    pub s3_region: String,
+    pub s3_endpoint: String,
+    pub s3_access_key: String,
+    pub s3_secret_key: String,
+    pub s3_path_style: bool,
+    pub s3_prefix: String,
+
+    pub archives_max_size: u64,
+    pub min_free_space: u64,
+
+    pub book_cache_dir: String,
+    pub book_cache_max_size: u64,
+
+    pub rate_limit_max_pauses: u32,
+
+    pub tfcs_global_rate: f64,
+    pub tfcs_user_rate: f64,
 }
 
 impl Config {
     pub fn load() -> Config {
         Config {
+            run_mode: get_env_or("RUN_MODE", RunMode::All),
+            worker_concurrency: get_env_or("WORKER_CONCURRENCY", 8),
+            shutdown_grace_period_secs: get_env_or("SHUTDOWN_GRACE_PERIOD_SECS", 30),
+
+            ready_check_timeout_secs: get_env_or("READY_CHECK_TIMEOUT_SECS", 5),
+            ready_max_queue_depth: get_env_or("READY_MAX_QUEUE_DEPTH", 100),
+
             api_key: get_env("API_KEY"),
+            admin_api_key: get_env_or("ADMIN_API_KEY", String::new()),
+            task_secret: get_env_or("TASK_SECRET", String::new()),
 
             library_api_key: get_env("LIBRARY_API_KEY"),
             library_url: get_env("LIBRARY_URL"),
+            library_timeout_secs: get_env_or("LIBRARY_TIMEOUT_SECS", 30),
+            library_max_retries: get_env_or("LIBRARY_MAX_RETRIES", 3),
+            library_circuit_threshold: get_env_or("LIBRARY_CIRCUIT_THRESHOLD", 5),
+            library_circuit_cooldown_secs: get_env_or("LIBRARY_CIRCUIT_COOLDOWN_SECS", 30),
+            library_page_size: get_env_or("LIBRARY_PAGE_SIZE", 50),
+            library_page_concurrency: get_env_or("LIBRARY_PAGE_CONCURRENCY", 4),
+            library_cache_ttl_secs: get_env_or("LIBRARY_CACHE_TTL_SECS", 10 * 60),
 
             cache_api_key: get_env("CACHE_API_KEY"),
             cache_url: get_env("CACHE_URL"),
+            cache_connect_timeout_secs: get_env_or("CACHE_CONNECT_TIMEOUT_SECS", 10),
+            cache_read_timeout_secs: get_env_or("CACHE_READ_TIMEOUT_SECS", 60),
+            cache_timeout_secs: get_env_or("CACHE_TIMEOUT_SECS", 10 * 60),
+
+            download_stall_window_secs: get_env_or("DOWNLOAD_STALL_WINDOW_SECS", 30),
+            download_min_throughput: get_env_or("DOWNLOAD_MIN_THROUGHPUT", 1024),
+            task_timeout_secs: get_env_or("TASK_TIMEOUT_SECS", 2 * 60 * 60),
+            task_stall_timeout_secs: get_env_or("TASK_STALL_TIMEOUT_SECS", 10 * 60),
 
             sentry_dsn: get_env("SENTRY_DSN"),
+
+            storage_dir: get_env_or("STORAGE_DIR", "/tmp/batch_downloader".to_string()),
+            instance_id: get_env_or(
+                "INSTANCE_ID",
+                std::env::var("HOSTNAME").unwrap_or_else(|_| "default".to_string()),
+            ),
+            storage_backend: get_env_or("STORAGE_BACKEND", StorageBackend::Local),
+            download_mode: get_env_or("DOWNLOAD_MODE", DownloadMode::Proxy),
+            presigned_url_ttl_secs: get_env_or("PRESIGNED_URL_TTL_SECS", 60 * 60),
+
+            task_store_backend: get_env_or("TASK_STORE_BACKEND", TaskStoreBackend::Memory),
+            redis_url: get_env_or("REDIS_URL", "redis://127.0.0.1/".to_string()),
+            redis_key_prefix: get_env_or("REDIS_KEY_PREFIX", "batch_downloader".to_string()),
+
+            s3_bucket: get_env_or("S3_BUCKET", String::new()),
+            s3_region: get_env_or("S3_REGION", "us-east-1".to_string()),
+            s3_endpoint: get_env_or("S3_ENDPOINT", String::new()),
+            s3_access_key: get_env_or("S3_ACCESS_KEY", String::new()),
+            s3_secret_key: get_env_or("S3_SECRET_KEY", String::new()),
+            s3_path_style: get_env_or("S3_PATH_STYLE", true),
+            s3_prefix: get_env_or("S3_PREFIX", "archives".to_string()),
+
+            archives_max_size: get_env_or("ARCHIVES_MAX_SIZE", 20 * 1024 * 1024 * 1024),
+            min_free_space: get_env_or("MIN_FREE_SPACE", 1024 * 1024 * 1024),
+
+            book_cache_dir: get_env_or("BOOK_CACHE_DIR", "/tmp/book_cache".to_string()),
+            book_cache_max_size: get_env_or("BOOK_CACHE_MAX_SIZE", 2 * 1024 * 1024 * 1024),
+
+            rate_limit_max_pauses: get_env_or("RATE_LIMIT_MAX_PAUSES", 10),
+
+            tfcs_global_rate: get_env_or("TFCS_GLOBAL_RATE", 20.0),
+            tfcs_user_rate: get_env_or("TFCS_USER_RATE", 5.0),
         }
     }
 }
@@ -0,0 +1,137 @@
+use std::fmt;
+
+use crate::{
+    services::cache_client::{CacheClientError, RateLimitError},
+    structures::ErrorCode,
+};
+
+/// Error returned by the archive building services.
+#[derive(Debug)]
+pub enum ServiceError {
+    /// Request to the library API failed or returned an unexpected body.
+    Library(reqwest::Error),
+    /// Library API circuit breaker is open after repeated failures.
+    LibraryUnavailable,
+    /// Request to TFCS failed or returned an unexpected status.
+    Tfcs(CacheClientError),
+    /// TFCS kept answering 429 after all retries.
+    RateLimited(RateLimitError),
+    Io(std::io::Error),
+    Zip(zip::result::ZipError),
+    /// Input or downloaded data was rejected.
+    Validation(String),
+    /// No books are available for the requested object and format.
+    NoBooks,
+    /// Download stalled or the task ran past its deadline.
+    Timeout(String),
+}
+
+impl ServiceError {
+    /// Stable code exposed to clients on the failed `Task`.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ServiceError::Library(_) => ErrorCode::LibraryError,
+            ServiceError::LibraryUnavailable => ErrorCode::LibraryUnavailable,
+            ServiceError::Tfcs(_) => ErrorCode::TfcsError,
+            ServiceError::RateLimited(_) => ErrorCode::RateLimited,
+            ServiceError::Io(_) => ErrorCode::IoError,
+            ServiceError::Zip(_) => ErrorCode::ZipError,
+            ServiceError::Validation(_) => ErrorCode::ValidationError,
+            ServiceError::NoBooks => ErrorCode::NoBooks,
+            ServiceError::Timeout(_) => ErrorCode::Timeout,
+        }
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServiceError::Library(e) => write!(f, "library error: {e}"),
+            ServiceError::LibraryUnavailable => write!(f, "library is unavailable"),
+            ServiceError::Tfcs(e) => write!(f, "TFCS error: {e}"),
+            ServiceError::RateLimited(e) => write!(f, "rate limited: {e}"),
+            ServiceError::Io(e) => write!(f, "io error: {e}"),
+            ServiceError::Zip(e) => write!(f, "zip error: {e}"),
+            ServiceError::Validation(e) => write!(f, "validation error: {e}"),
+            ServiceError::NoBooks => write!(f, "no books"),
+            ServiceError::Timeout(e) => write!(f, "timeout: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServiceError::Library(e) => Some(e),
+            ServiceError::Tfcs(e) => Some(e),
+            ServiceError::RateLimited(e) => Some(e),
+            ServiceError::Io(e) => Some(e),
+            ServiceError::Zip(e) => Some(e),
+            ServiceError::LibraryUnavailable
+            | ServiceError::Validation(_)
+            | ServiceError::NoBooks
+            | ServiceError::Timeout(_) => None,
+        }
+    }
+}
+
+impl From<CacheClientError> for ServiceError {
+    fn from(e: CacheClientError) -> Self {
+        match e {
+            CacheClientError::RateLimited(e) => ServiceError::RateLimited(e),
+            e => ServiceError::Tfcs(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for ServiceError {
+    fn from(e: std::io::Error) -> Self {
+        ServiceError::Io(e)
+    }
+}
+
+impl From<zip::result::ZipError> for ServiceError {
+    fn from(e: zip::result::ZipError) -> Self {
+        ServiceError::Zip(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ServiceError;
+    use crate::{
+        services::cache_client::{CacheClientError, RateLimitError},
+        structures::ErrorCode,
+    };
+
+    #[test]
+    fn rate_limit_is_not_a_generic_tfcs_error() {
+        let err: ServiceError = CacheClientError::RateLimited(RateLimitError {
+            operation: "cache_hit_download".to_string(),
+            retry_after_secs: 5,
+            attempts: 3,
+        })
+        .into();
+
+        assert_eq!(err.code(), ErrorCode::RateLimited);
+
+        let err: ServiceError = CacheClientError::CannotCloneRequest.into();
+        assert_eq!(err.code(), ErrorCode::TfcsError);
+    }
+
+    #[test]
+    fn error_codes_are_snake_case() {
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::LibraryError).unwrap(),
+            "\"library_error\""
+        );
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::NoBooks).unwrap(),
+            "\"no_books\""
+        );
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::InsufficientStorage).unwrap(),
+            format!("\"{}\"", ErrorCode::InsufficientStorage.as_str())
+        );
+    }
+}
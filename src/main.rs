@@ -1,24 +1,84 @@
 pub mod config;
+pub mod errors;
 pub mod services;
 pub mod structures;
 pub mod views;
 
 use sentry::{integrations::debug_images::DebugImagesIntegration, types::Dsn, ClientOptions};
 use sentry_tracing::EventFilter;
-use std::{net::SocketAddr, str::FromStr};
-use tracing::info;
+use std::{net::SocketAddr, str::FromStr, time::Duration};
+use tracing::{error, info};
 use tracing_subscriber::{filter, layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::views::get_router;
+use crate::{
+    config::{RunMode, StorageBackend, TaskStoreBackend},
+    views::{get_router, get_worker_router},
+};
+
+/// Resolves once the instance is ready to stop: on SIGTERM or SIGINT new
+/// tasks are rejected and running builds get `SHUTDOWN_GRACE_PERIOD_SECS`
+/// to finish. The API keeps answering meanwhile.
+async fn shutdown(run_mode: RunMode) {
+    services::shutdown::wait_for_signal().await;
+
+    info!("Shutting down...");
+    services::shutdown::start();
+
+    if run_mode.builds_archives() {
+        let grace_period = Duration::from_secs(config::CONFIG.shutdown_grace_period_secs);
+        services::task_creator::drain_builds(grace_period).await;
+    }
+}
 
 async fn start_app() {
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
 
-    let app = get_router().await;
+    let run_mode = config::CONFIG.run_mode;
+
+    if config::CONFIG.task_store_backend == TaskStoreBackend::Redis {
+        // Queued archive passwords are encrypted with it.
+        assert!(
+            !config::CONFIG.task_secret.is_empty(),
+            "TASK_STORE_BACKEND=redis needs TASK_SECRET"
+        );
+        // Other instances serve and remove the archives of shared tasks.
+        assert!(
+            config::CONFIG.storage_backend == StorageBackend::S3,
+            "TASK_STORE_BACKEND=redis needs STORAGE_BACKEND=s3"
+        );
+    }
+
+    if run_mode != RunMode::All {
+        // Tasks have to be handed over between the instances.
+        assert!(
+            config::CONFIG.task_store_backend == TaskStoreBackend::Redis,
+            "RUN_MODE={run_mode:?} needs TASK_STORE_BACKEND=redis"
+        );
+    }
+
+    if let Err(err) = services::storage::cleanup_storage().await {
+        error!("Storage cleanup failed: {}", err);
+    }
+
+    let app = if run_mode.serves_api() {
+        get_router().await
+    } else {
+        get_worker_router().await
+    };
+
+    if run_mode.builds_archives() {
+        tokio::spawn(services::watchdog::run());
+        tokio::spawn(services::task_queue::run_worker());
+    }
+
+    info!("Run mode: {:?}", run_mode);
 
     info!("Start webserver...");
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown(run_mode))
+        .await
+        .unwrap();
     info!("Webserver shutdown...");
 }
 
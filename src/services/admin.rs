@@ -0,0 +1,208 @@
+use std::fmt;
+
+use smallvec::SmallVec;
+
+use crate::structures::{CreateTask, Task, TaskDetails, TaskListQuery, TaskStatus};
+
+use super::{
+    task_creator::create_task,
+    task_store::{TaskStoreError, TASKS},
+    utils::{get_key, unix_time},
+};
+
+#[derive(Debug)]
+pub enum AdminError {
+    NotFound,
+    /// The action doesn't apply to the task in its current state.
+    Conflict(&'static str),
+    Store(TaskStoreError),
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdminError::NotFound => write!(f, "task not found"),
+            AdminError::Conflict(e) => write!(f, "conflict: {e}"),
+            AdminError::Store(e) => write!(f, "task store error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AdminError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AdminError::Store(e) => Some(e),
+            AdminError::NotFound | AdminError::Conflict(_) => None,
+        }
+    }
+}
+
+impl From<TaskStoreError> for AdminError {
+    fn from(e: TaskStoreError) -> Self {
+        AdminError::Store(e)
+    }
+}
+
+fn matches_query(
+    query: &TaskListQuery,
+    task: &Task,
+    requested_by: Option<&SmallVec<[i64; 4]>>,
+    now: u64,
+) -> bool {
+    let age_secs = now.saturating_sub(task.created_at);
+
+    query.status.as_ref().is_none_or(|v| *v == task.status)
+        && query.object_type.as_ref().is_none_or(|v| {
+            task.request
+                .as_ref()
+                .is_some_and(|request| request.object_type == *v)
+        })
+        && query
+            .user_id
+            .is_none_or(|v| requested_by.is_some_and(|users| users.contains(&v)))
+        && query.min_age_secs.is_none_or(|v| age_secs >= v)
+        && query.max_age_secs.is_none_or(|v| age_secs <= v)
+}
+
+/// Tasks matching `query`, newest first.
+pub async fn list_tasks(query: &TaskListQuery) -> Result<Vec<Task>, TaskStoreError> {
+    let now = unix_time();
+    let mut result = vec![];
+
+    for task in TASKS.list().await? {
+        let requested_by = match query.user_id {
+            Some(_) => Some(TASKS.get_requesters(&task.id).await?),
+            None => None,
+        };
+
+        if matches_query(query, &task, requested_by.as_ref(), now) {
+            result.push(task);
+        }
+    }
+
+    result.sort_by_key(|task| std::cmp::Reverse(task.created_at));
+
+    Ok(result)
+}
+
+pub async fn get_task_details(key: &str) -> Result<TaskDetails, AdminError> {
+    let task = TASKS.get(key).await?.ok_or(AdminError::NotFound)?;
+    let now = unix_time();
+
+    Ok(TaskDetails {
+        library_total: task.library_total,
+        requested_by: TASKS.get_requesters(key).await?,
+        age_secs: now.saturating_sub(task.created_at),
+        duration_secs: task.updated_at.saturating_sub(task.created_at),
+        task,
+    })
+}
+
+/// Drop the task and its archive, the next request builds it again.
+pub async fn expire_task(key: &str) -> Result<(), AdminError> {
+    if !TASKS.remove(key).await? {
+        return Err(AdminError::NotFound);
+    }
+
+    Ok(())
+}
+
+/// Build the task again from its stored request.
+pub async fn rebuild_task(key: &str) -> Result<Task, AdminError> {
+    let task = TASKS.get(key).await?.ok_or(AdminError::NotFound)?;
+
+    if task.status.is_active() {
+        return Err(AdminError::Conflict("task is being built"));
+    }
+
+    let Some(request) = task.request else {
+        return Err(AdminError::Conflict("task request is unknown"));
+    };
+
+    if request.encrypted {
+        return Err(AdminError::Conflict("archive password is unknown"));
+    }
+
+    let data = CreateTask {
+        object_id: request.object_id,
+        object_type: request.object_type,
+        file_format: request.file_format,
+        allowed_langs: request.allowed_langs,
+        user_id: None,
+        normalized: request.normalized,
+        password: None,
+        force_rebuild: true,
+    };
+
+    if get_key(data.clone()) != key {
+        return Err(AdminError::Conflict("task request is unknown"));
+    }
+
+    Ok(create_task(data).await?)
+}
+
+/// Drop all failed tasks. Returns the number of removed tasks.
+pub async fn purge_failed_tasks() -> Result<usize, TaskStoreError> {
+    let mut removed: usize = 0;
+
+    for task in TASKS.list().await? {
+        if task.status == TaskStatus::Failed && TASKS.remove(&task.id).await? {
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use smallvec::{smallvec, SmallVec};
+
+    use super::matches_query;
+    use crate::structures::{CreateTask, ObjectType, Task, TaskListQuery, TaskRequest, TaskStatus};
+
+    fn task(status: TaskStatus, object_type: ObjectType) -> Task {
+        let data = CreateTask {
+            object_type,
+            ..CreateTask::fixture()
+        };
+
+        Task {
+            request: Some(TaskRequest::from(&data)),
+            ..Task::fixture("task", status, 100)
+        }
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        let task = task(TaskStatus::Complete, ObjectType::Author);
+
+        assert!(matches_query(&TaskListQuery::default(), &task, None, 200));
+    }
+
+    #[test]
+    fn query_filters_are_combined() {
+        let task = task(TaskStatus::Failed, ObjectType::Sequence);
+        let users: SmallVec<[i64; 4]> = smallvec![7];
+
+        let query = TaskListQuery {
+            status: Some(TaskStatus::Failed),
+            object_type: Some(ObjectType::Sequence),
+            user_id: Some(7),
+            min_age_secs: Some(50),
+            max_age_secs: Some(150),
+        };
+        assert!(matches_query(&query, &task, Some(&users), 200));
+
+        // Too young.
+        assert!(!matches_query(&query, &task, Some(&users), 120));
+        // Requested by someone else.
+        assert!(!matches_query(&query, &task, None, 200));
+
+        let query = TaskListQuery {
+            object_type: Some(ObjectType::Author),
+            ..TaskListQuery::default()
+        };
+        assert!(!matches_query(&query, &task, None, 200));
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use moka::{future::Cache, notification::RemovalCause, policy::EvictionPolicy};
+use once_cell::sync::Lazy;
+use tempfile::SpooledTempFile;
+use tracing::log;
+
+use crate::config;
+
+/// Book file stored in the local cache directory.
+#[derive(Clone)]
+pub struct CachedBook {
+    pub filename: String,
+    pub size: u64,
+}
+
+/// Local on-disk cache of downloaded books shared by all tasks.
+///
+/// Keyed by `(book id, format, normalized)`, bounded by
+/// `BOOK_CACHE_MAX_SIZE` bytes with LRU eviction. Files of evicted
+/// entries are removed from `BOOK_CACHE_DIR`.
+static BOOK_CACHE: Lazy<Cache<String, CachedBook>> = Lazy::new(|| {
+    if let Err(err) = std::fs::create_dir_all(&config::CONFIG.book_cache_dir) {
+        log::error!("Can't create book cache dir: {}", err);
+    }
+    // Files left from the previous run are not tracked by the cache.
+    remove_cache_files(Path::new(&config::CONFIG.book_cache_dir));
+
+    Cache::builder()
+        .eviction_policy(EvictionPolicy::lru())
+        .weigher(|_key, value: &CachedBook| value.size.try_into().unwrap_or(u32::MAX))
+        .max_capacity(config::CONFIG.book_cache_max_size)
+        .async_eviction_listener(|key: Arc<String>, value: CachedBook, reason| {
+            Box::pin(async move {
+                metrics::gauge!("book_cache_size_bytes").decrement(value.size as f64);
+
+                // The replacing entry has already overwritten the same file.
+                if reason == RemovalCause::Replaced {
+                    return;
+                }
+
+                let _ = tokio::fs::remove_file(get_path(&key)).await;
+            })
+        })
+        .build()
+});
+
+const PARTIAL_SUFFIX: &str = ".partial";
+
+fn get_cache_key(book_id: u64, file_type: &str, normalized: bool) -> String {
+    format!("{book_id}_{file_type}_{normalized}")
+}
+
+/// Whether the file is a cached book or a partial one, see `get_cache_key`.
+fn is_cache_file(name: &str) -> bool {
+    let Some((book_id, rest)) = name.split_once('_') else {
+        return false;
+    };
+
+    !book_id.is_empty()
+        && book_id.chars().all(|c| c.is_ascii_digit())
+        && (rest.ends_with("_true") || rest.ends_with("_false") || rest.ends_with(PARTIAL_SUFFIX))
+}
+
+/// Remove cached books from `dir`, leaving other files alone:
+/// `BOOK_CACHE_DIR` may be shared with something else.
+fn remove_cache_files(dir: &Path) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(err) => {
+            log::warn!("Can't read book cache dir: {}", err);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let is_cached = entry.file_name().to_str().is_some_and(is_cache_file)
+            && entry.file_type().is_ok_and(|v| v.is_file());
+
+        if is_cached {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+fn get_path(key: &str) -> PathBuf {
+    PathBuf::from(&config::CONFIG.book_cache_dir).join(key)
+}
+
+/// Look up a book in the local cache and copy it into a fresh tempfile.
+pub async fn get(
+    book_id: u64,
+    file_type: &str,
+    normalized: bool,
+) -> Option<(SpooledTempFile, String)> {
+    let key = get_cache_key(book_id, file_type, normalized);
+
+    let cached = match BOOK_CACHE.get(&key).await {
+        Some(v) => v,
+        None => {
+            metrics::counter!("book_cache_misses_total").increment(1);
+            return None;
+        }
+    };
+
+    let mut file = match File::open(get_path(&key)) {
+        Ok(v) => v,
+        Err(err) => {
+            log::warn!("Cached book {} is unreadable: {}", key, err);
+            BOOK_CACHE.invalidate(&key).await;
+            metrics::counter!("book_cache_misses_total").increment(1);
+            return None;
+        }
+    };
+
+    let mut tmp_file = tempfile::spooled_tempfile(5 * 1024 * 1024);
+    if let Err(err) = std::io::copy(&mut file, &mut tmp_file) {
+        log::warn!("Can't read cached book {}: {}", key, err);
+        BOOK_CACHE.invalidate(&key).await;
+        metrics::counter!("book_cache_misses_total").increment(1);
+        return None;
+    }
+    tmp_file.seek(SeekFrom::Start(0)).ok()?;
+
+    metrics::counter!("book_cache_hits_total").increment(1);
+
+    Some((tmp_file, cached.filename))
+}
+
+/// Store a downloaded book in the local cache.
+///
+/// Failures are only logged: the cache is an optimisation and must not
+/// fail the download. `tmp_file` is rewound before returning.
+pub async fn put(
+    book_id: u64,
+    file_type: &str,
+    normalized: bool,
+    tmp_file: &mut SpooledTempFile,
+    filename: &str,
+) {
+    let key = get_cache_key(book_id, file_type, normalized);
+    let path = get_path(&key);
+
+    // Other tasks may store the same book concurrently, so each one writes
+    // its own partial file and renames it in place once complete.
+    let written = (|| -> std::io::Result<u64> {
+        let mut file = tempfile::Builder::new()
+            .prefix(&format!("{key}."))
+            .suffix(PARTIAL_SUFFIX)
+            .tempfile_in(&config::CONFIG.book_cache_dir)?;
+        tmp_file.seek(SeekFrom::Start(0))?;
+        let size = std::io::copy(tmp_file, &mut file)?;
+        file.persist(&path)?;
+        Ok(size)
+    })();
+
+    let _ = tmp_file.seek(SeekFrom::Start(0));
+
+    let size = match written {
+        Ok(v) => v,
+        Err(err) => {
+            log::warn!("Can't store book {} in cache: {}", key, err);
+            return;
+        }
+    };
+
+    BOOK_CACHE
+        .insert(
+            key,
+            CachedBook {
+                filename: filename.to_string(),
+                size,
+            },
+        )
+        .await;
+    metrics::gauge!("book_cache_size_bytes").increment(size as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_cache_key, is_cache_file, remove_cache_files};
+
+    #[test]
+    fn only_cache_files_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let cached = get_cache_key(42, "fb2", true);
+        let partial = format!("{cached}.a1b2c3.partial");
+        for name in [
+            cached.as_str(),
+            partial.as_str(),
+            "archive.zip",
+            "notes_true",
+        ] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        assert!(is_cache_file(&get_cache_key(1, "fb2.zip", false)));
+        remove_cache_files(dir.path());
+
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["archive.zip", "notes_true"]);
+    }
+}
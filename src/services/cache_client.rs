@@ -1,11 +1,24 @@
+use std::time::Duration;
+
 use once_cell::sync::Lazy;
 use reqwest::{Request, Response, StatusCode};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tracing::warn;
 
 use crate::config;
 
-pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+use super::{tfcs_limiter, watchdog};
+
+pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(
+            config::CONFIG.cache_connect_timeout_secs,
+        ))
+        .read_timeout(Duration::from_secs(config::CONFIG.cache_read_timeout_secs))
+        .timeout(Duration::from_secs(config::CONFIG.cache_timeout_secs))
+        .build()
+        .unwrap()
+});
 
 const MAX_RETRIES: u32 = 3;
 const DEFAULT_RETRY_AFTER_SECS: u64 = 5;
@@ -106,6 +119,8 @@ fn build_request(
 
 /// Send a request to TFCS with retry on 429 responses.
 ///
+/// Every attempt first waits for the shared `tfcs_limiter`, which is fed
+/// back with the outcome so that concurrent tasks slow down together.
 /// Retries up to MAX_RETRIES times with exponential backoff starting from
 /// the `Retry-After` value returned by TFCS.
 ///
@@ -118,8 +133,11 @@ pub async fn call_with_retry(request: Request) -> Result<Response, CacheClientEr
         .get("X-User-Id")
         .and_then(|v| v.to_str().ok())
         .map(|s| s.to_string());
+    let limiter_user_id = user_id.as_deref().and_then(|v| v.parse::<i64>().ok());
 
     loop {
+        tfcs_limiter::acquire(limiter_user_id).await;
+
         // Clone the request before sending so we can retry.
         // reqwest consumes the request body on send, so we need try_clone.
         let cloned = request
@@ -128,14 +146,18 @@ pub async fn call_with_retry(request: Request) -> Result<Response, CacheClientEr
         let response = CLIENT.execute(cloned).await?;
 
         if response.status() != StatusCode::TOO_MANY_REQUESTS {
+            tfcs_limiter::on_success(limiter_user_id);
             return Ok(response);
         }
 
         attempt += 1;
 
         let retry_after = extract_retry_after(response).await;
+        tfcs_limiter::on_rate_limited(limiter_user_id, retry_after);
         let operation = extract_operation(request.url().path());
 
+        metrics::counter!("tfcs_rate_limited_total", "operation" => operation).increment(1);
+
         warn!(
             operation,
             retry_after_secs = retry_after,
@@ -157,10 +179,29 @@ pub async fn call_with_retry(request: Request) -> Result<Response, CacheClientEr
 
         // Exponential backoff: retry_after * 2^(attempt-1), capped at MAX_BACKOFF_SECS
         let backoff_secs = (retry_after * 2u64.pow(attempt - 1)).min(MAX_BACKOFF_SECS);
-        tokio::time::sleep(std::time::Duration::from_secs(backoff_secs)).await;
+        metrics::counter!("tfcs_backoff_seconds_total", "operation" => operation)
+            .increment(backoff_secs);
+        watchdog::beat_current_with_pause(Duration::from_secs(backoff_secs));
+        tokio::time::sleep(Duration::from_secs(backoff_secs)).await;
     }
 }
 
+/// Check that TFCS answers within `timeout`, bypassing the rate limiter.
+/// Any response but a server error counts.
+pub async fn check_reachable(timeout: Duration) -> Result<(), String> {
+    let response = build_request(reqwest::Method::GET, "/", None)
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if response.status().is_server_error() {
+        return Err(format!("unexpected status {}", response.status()));
+    }
+
+    Ok(())
+}
+
 // ---- Public convenience functions ----
 
 /// GET a resource from TFCS (cache hit / cache hit copy).
@@ -219,6 +260,13 @@ pub async fn cache_delete(
     Ok(response)
 }
 
+/// Body of `cache_update_cache` asking TFCS to re-cache a single object.
+#[derive(Serialize)]
+pub struct UpdateCacheRequest<'a> {
+    pub object_id: u64,
+    pub object_type: &'a str,
+}
+
 /// POST to update_cache.
 ///
 /// Note: TFCS does not rate-limit update_cache per its API contract,
@@ -239,6 +287,7 @@ pub enum CacheClientError {
     Reqwest(reqwest::Error),
     RateLimited(RateLimitError),
     CannotCloneRequest,
+    UnexpectedStatus(StatusCode),
 }
 
 impl std::fmt::Display for CacheClientError {
@@ -249,6 +298,9 @@ impl std::fmt::Display for CacheClientError {
             CacheClientError::CannotCloneRequest => {
                 write!(f, "cannot clone request body for retry")
             }
+            CacheClientError::UnexpectedStatus(status) => {
+                write!(f, "unexpected status code {status}")
+            }
         }
     }
 }
@@ -258,7 +310,7 @@ impl std::error::Error for CacheClientError {
         match self {
             CacheClientError::Reqwest(e) => Some(e),
             CacheClientError::RateLimited(e) => Some(e),
-            CacheClientError::CannotCloneRequest => None,
+            CacheClientError::CannotCloneRequest | CacheClientError::UnexpectedStatus(_) => None,
         }
     }
 }
@@ -0,0 +1,121 @@
+use base64::{engine::general_purpose, Engine};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha2::Sha256;
+use tracing::log;
+
+use crate::config;
+
+/// Keys derived from the server-side secret.
+pub struct TaskSecret {
+    digest_key: Vec<u8>,
+    cipher: ChaCha20Poly1305,
+}
+
+impl TaskSecret {
+    pub fn new(secret: &[u8]) -> Self {
+        TaskSecret {
+            digest_key: secret.to_vec(),
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&hmac(secret, "queued password"))),
+        }
+    }
+
+    /// Digest of the archive password. Can't be brute-forced without the
+    /// secret, so it's safe to mix into public task ids.
+    pub fn password_digest(&self, password: &str) -> String {
+        hmac(&self.digest_key, password)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Encrypt the archive password, so that it's never stored in plaintext
+    /// while the task waits in a shared queue.
+    pub fn encrypt_password(&self, password: &str) -> String {
+        let mut nonce = [0; NONCE_SIZE];
+        getrandom::fill(&mut nonce).expect("Cannot generate nonce");
+
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), password.as_bytes())
+            .expect("Encryption of a short value can't fail");
+
+        general_purpose::STANDARD.encode([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    pub fn decrypt_password(&self, value: &str) -> Result<String, String> {
+        let data = general_purpose::STANDARD
+            .decode(value)
+            .map_err(|err| format!("invalid base64: {err}"))?;
+
+        if data.len() < NONCE_SIZE {
+            return Err("value is too short".to_string());
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "can't decrypt, is TASK_SECRET the same on all instances?".to_string())?;
+
+        String::from_utf8(plaintext).map_err(|err| format!("invalid utf-8: {err}"))
+    }
+}
+
+/// Keys of `TASK_SECRET`, or of a random secret if it isn't set.
+/// Instances sharing tasks need the same secret.
+///
+/// Loads `CONFIG`, so only touch it when there is a password.
+pub static TASK_SECRET: Lazy<TaskSecret> = Lazy::new(|| {
+    if !config::CONFIG.task_secret.is_empty() {
+        return TaskSecret::new(config::CONFIG.task_secret.as_bytes());
+    }
+
+    log::warn!("TASK_SECRET is not set, using a random one");
+
+    let mut secret = vec![0; 32];
+    getrandom::fill(&mut secret).expect("Cannot generate TASK_SECRET");
+    TaskSecret::new(&secret)
+});
+
+const NONCE_SIZE: usize = 12;
+
+fn hmac(secret: &[u8], value: &str) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts any key size");
+    mac.update(value.as_bytes());
+
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TaskSecret;
+
+    #[test]
+    fn digest_depends_on_secret() {
+        let secret = TaskSecret::new(b"first");
+        let first = secret.password_digest("password");
+
+        assert_eq!(first, secret.password_digest("password"));
+        assert_ne!(
+            first,
+            TaskSecret::new(b"second").password_digest("password")
+        );
+        assert_ne!(first, secret.password_digest("other"));
+        assert!(!first.contains("password"));
+    }
+
+    #[test]
+    fn password_roundtrip_needs_same_secret() {
+        let secret = TaskSecret::new(b"first");
+        let first = secret.encrypt_password("password");
+
+        assert_ne!(first, secret.encrypt_password("password"));
+        assert!(!first.contains("password"));
+
+        assert_eq!(secret.decrypt_password(&first).unwrap(), "password");
+        assert!(TaskSecret::new(b"second").decrypt_password(&first).is_err());
+        assert!(secret.decrypt_password("c2hvcnQ=").is_err());
+    }
+}
@@ -1,59 +1,189 @@
-use std::fmt;
-
 use base64::{engine::general_purpose, Engine};
-use reqwest::StatusCode;
+use reqwest::{header::HeaderMap, StatusCode};
 use smartstring::alias::String as SmartString;
 use tempfile::SpooledTempFile;
 use tracing::log;
 
-use super::{cache_client, utils::response_to_tempfile};
+use crate::errors::ServiceError;
+
+use super::{
+    book_cache,
+    cache_client::{self, CacheClientError, UpdateCacheRequest},
+    utils::{get_fallback_filename, response_to_tempfile},
+    validation::validate_book,
+};
+
+/// A book failing validation is downloaded once more before giving up.
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 2;
+
+pub async fn download(
+    book_id: u64,
+    file_type: SmartString,
+    user_id: Option<i64>,
+    normalized: bool,
+) -> Result<(SpooledTempFile, String), ServiceError> {
+    if let Some(cached) = book_cache::get(book_id, &file_type, normalized).await {
+        return Ok(cached);
+    }
+
+    let mut attempt: u32 = 1;
+
+    let result = loop {
+        match fetch(book_id, &file_type, user_id, normalized).await {
+            Err(ServiceError::Validation(reason)) if attempt < MAX_DOWNLOAD_ATTEMPTS => {
+                log::warn!(
+                    "Book {} ({}) failed validation, downloading again: {}",
+                    book_id,
+                    file_type,
+                    reason
+                );
+                attempt += 1;
+            }
+            result => break result,
+        }
+    };
+
+    let (mut output_file, filename) = match result {
+        Err(err) if is_broken_cache_entry(&err) => {
+            log::warn!(
+                "Book {} ({}) looks broken in TFCS, healing: {}",
+                book_id,
+                file_type,
+                err
+            );
+            heal_cache_entry(book_id, &file_type, user_id).await;
 
-#[derive(Debug, Clone)]
-struct DownloadError {
-    status_code: StatusCode,
+            let result = fetch(book_id, &file_type, user_id, normalized).await;
+            let heal_result = if result.is_ok() { "healed" } else { "failed" };
+            log::info!(
+                "Healing of book {} ({}): {}",
+                book_id,
+                file_type,
+                heal_result
+            );
+            metrics::counter!("tfcs_cache_heal_total", "result" => heal_result).increment(1);
+
+            result?
+        }
+        result => result?,
+    };
+
+    book_cache::put(book_id, &file_type, normalized, &mut output_file, &filename).await;
+
+    Ok((output_file, filename))
 }
 
-impl fmt::Display for DownloadError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Status code is {0}", self.status_code)
+/// Decode the book filename TFCS sends base64-encoded in `x-filename-b64`.
+fn decode_filename(headers: &HeaderMap) -> Result<String, String> {
+    let header = headers
+        .get("x-filename-b64")
+        .ok_or_else(|| "no x-filename-b64 header".to_string())?;
+
+    let decoded = general_purpose::STANDARD
+        .decode(header.as_bytes())
+        .map_err(|err| format!("invalid base64: {err}"))?;
+
+    let filename = String::from_utf8(decoded).map_err(|err| format!("invalid utf-8: {err}"))?;
+
+    if filename.is_empty() {
+        return Err("empty filename".to_string());
     }
+
+    Ok(filename)
 }
 
-impl std::error::Error for DownloadError {}
+/// Errors that persist because of a bad TFCS cache entry rather than
+/// a transient problem.
+///
+/// Server errors are left alone: during a TFCS outage healing would drop
+/// valid cache entries of every book. 404 means the book itself is missing.
+fn is_broken_cache_entry(err: &ServiceError) -> bool {
+    match err {
+        ServiceError::Validation(_) => true,
+        ServiceError::Tfcs(CacheClientError::UnexpectedStatus(status)) => {
+            // TFCS answered, but not with the book.
+            status.is_success() || *status == StatusCode::GONE
+        }
+        _ => false,
+    }
+}
 
-pub async fn download(
+/// Drop the TFCS cache entry of a book and ask TFCS to cache it again.
+async fn heal_cache_entry(book_id: u64, file_type: &str, user_id: Option<i64>) {
+    match cache_client::cache_delete(book_id, file_type, user_id).await {
+        Ok(response) if !response.status().is_success() => log::warn!(
+            "Can't delete TFCS cache of book {} ({}): status {}",
+            book_id,
+            file_type,
+            response.status()
+        ),
+        Ok(_) => (),
+        Err(err) => log::warn!(
+            "Can't delete TFCS cache of book {} ({}): {}",
+            book_id,
+            file_type,
+            err
+        ),
+    };
+
+    let body = UpdateCacheRequest {
+        object_id: book_id,
+        object_type: file_type,
+    };
+
+    match cache_client::cache_update_cache(body, user_id).await {
+        Ok(response) if !response.status().is_success() => log::warn!(
+            "Can't update TFCS cache of book {} ({}): status {}",
+            book_id,
+            file_type,
+            response.status()
+        ),
+        Ok(_) => (),
+        Err(err) => log::warn!(
+            "Can't update TFCS cache of book {} ({}): {}",
+            book_id,
+            file_type,
+            err
+        ),
+    };
+}
+
+/// Download a book from TFCS and validate its content.
+async fn fetch(
     book_id: u64,
-    file_type: SmartString,
+    file_type: &str,
     user_id: Option<i64>,
     normalized: bool,
-) -> Result<(SpooledTempFile, String), Box<dyn std::error::Error + Send + Sync>> {
-    let response = cache_client::cache_download(book_id, &file_type, user_id, normalized).await?;
+) -> Result<(SpooledTempFile, String), ServiceError> {
+    let response = cache_client::cache_download(book_id, file_type, user_id, normalized).await?;
 
     match response.status() {
         StatusCode::OK => {}
         // 429 is handled by cache_client::cache_download returning CacheClientError::RateLimited
-        // which propagates up as-is
+        // which is converted into ServiceError::RateLimited
         status => {
-            return Err(Box::new(DownloadError {
-                status_code: status,
-            }));
+            return Err(CacheClientError::UnexpectedStatus(status).into());
         }
     };
 
     let mut response = response;
-    let headers = response.headers();
 
-    let base64_encoder = general_purpose::STANDARD;
+    let filename = match decode_filename(response.headers()) {
+        Ok(v) => v,
+        Err(reason) => {
+            log::warn!(
+                "Can't get filename of book {} ({}): {}",
+                book_id,
+                file_type,
+                reason
+            );
+            get_fallback_filename(book_id, file_type, normalized)
+        }
+    };
 
-    let filename = std::str::from_utf8(
-        &base64_encoder
-            .decode(headers.get("x-filename-b64").unwrap())
-            .unwrap(),
-    )
-    .unwrap()
-    .to_string();
+    let expected_size = response.content_length();
 
-    let output_file = match response_to_tempfile(&mut response).await {
+    let (mut output_file, received_size) = match response_to_tempfile(&mut response).await {
         Ok(v) => v,
         Err(err) => {
             log::error!("Error: {}", err);
@@ -61,5 +191,67 @@ pub async fn download(
         }
     };
 
-    Ok((output_file.0, filename))
+    validate_book(
+        &mut output_file,
+        file_type,
+        expected_size,
+        received_size as u64,
+    )
+    .map_err(ServiceError::Validation)?;
+
+    Ok((output_file, filename))
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::{
+        header::{HeaderMap, HeaderValue},
+        StatusCode,
+    };
+
+    use super::{decode_filename, is_broken_cache_entry};
+    use crate::{errors::ServiceError, services::cache_client::CacheClientError};
+
+    fn headers(value: Option<&[u8]>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = value {
+            headers.insert("x-filename-b64", HeaderValue::from_bytes(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn decodes_valid_filename() {
+        // "Кот.fb2"
+        let filename = decode_filename(&headers(Some(b"0JrQvtGCLmZiMg=="))).unwrap();
+        assert_eq!(filename, "Кот.fb2");
+    }
+
+    #[test]
+    fn malformed_headers_are_errors() {
+        assert!(decode_filename(&headers(None)).is_err());
+        assert!(decode_filename(&headers(Some(b"not base64!"))).is_err());
+        // base64 of 0xFF 0xFE, which is not UTF-8.
+        assert!(decode_filename(&headers(Some(b"//4="))).is_err());
+        assert!(decode_filename(&headers(Some(b""))).is_err());
+    }
+
+    #[test]
+    fn only_persistent_errors_heal_cache() {
+        let status_error = |status| ServiceError::Tfcs(CacheClientError::UnexpectedStatus(status));
+
+        assert!(is_broken_cache_entry(&ServiceError::Validation(
+            "file is empty".to_string()
+        )));
+        assert!(is_broken_cache_entry(&status_error(StatusCode::NO_CONTENT)));
+        assert!(is_broken_cache_entry(&status_error(StatusCode::GONE)));
+
+        assert!(!is_broken_cache_entry(&status_error(StatusCode::NOT_FOUND)));
+        assert!(!is_broken_cache_entry(&status_error(
+            StatusCode::INTERNAL_SERVER_ERROR
+        )));
+        assert!(!is_broken_cache_entry(&status_error(
+            StatusCode::BAD_GATEWAY
+        )));
+    }
 }
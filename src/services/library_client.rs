@@ -1,13 +1,78 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use moka::future::Cache;
 use once_cell::sync::Lazy;
 use serde::{de::DeserializeOwned, Deserialize};
 use smallvec::SmallVec;
 use smartstring::alias::String as SmartString;
 use tracing::log;
 
-use crate::config;
+use crate::{config, errors::ServiceError, structures::ObjectType};
+
+use super::task_store::{TaskStoreError, TASKS};
+
+pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(config::CONFIG.library_timeout_secs))
+        .build()
+        .unwrap()
+});
+/// Base delay of the exponential backoff between retries.
+const RETRY_BASE_DELAY_MS: u64 = 500;
+
+/// Fails library requests fast after `threshold` consecutive failures,
+/// until `cooldown` passes and a trial request is let through.
+struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            consecutive_failures: 0,
+            open_until: None,
+        }
+    }
+
+    fn allow_request(&self, now: Instant) -> bool {
+        self.open_until.is_none_or(|v| v <= now)
+    }
+
+    fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.open_until = None;
+    }
+
+    fn record_failure(&mut self, now: Instant) {
+        self.consecutive_failures += 1;
 
-pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
-const PAGE_SIZE: &str = "50";
+        if self.consecutive_failures >= self.threshold {
+            self.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+static CIRCUIT_BREAKER: Lazy<Mutex<CircuitBreaker>> = Lazy::new(|| {
+    Mutex::new(CircuitBreaker::new(
+        config::CONFIG.library_circuit_threshold,
+        Duration::from_secs(config::CONFIG.library_circuit_cooldown_secs),
+    ))
+});
+
+/// 5xx responses, timeouts and connection errors are worth retrying.
+fn is_retryable(err: &reqwest::Error) -> bool {
+    err.is_timeout()
+        || err.is_connect()
+        || err.status().is_some_and(|status| status.is_server_error())
+}
 
 fn get_allowed_langs_params(
     allowed_langs: SmallVec<[SmartString; 3]>,
@@ -18,30 +83,93 @@ fn get_allowed_langs_params(
         .collect()
 }
 
-async fn _make_request<T>(
-    url: &str,
-    params: Vec<(&str, SmartString)>,
-) -> Result<T, Box<dyn std::error::Error + Send + Sync>>
+async fn _make_request<T>(url: &str, params: Vec<(&str, SmartString)>) -> Result<T, ServiceError>
 where
     T: DeserializeOwned,
 {
-    let response = CLIENT
-        .get(format!("{}{}", &config::CONFIG.library_url, url))
-        .query(&params)
-        .header("Authorization", &config::CONFIG.library_api_key)
-        .send()
-        .await?
-        .error_for_status()?;
+    if !CIRCUIT_BREAKER
+        .lock()
+        .unwrap()
+        .allow_request(Instant::now())
+    {
+        return Err(ServiceError::LibraryUnavailable);
+    }
+
+    let mut attempt: u32 = 0;
+
+    let response = loop {
+        let result = CLIENT
+            .get(format!("{}{}", &config::CONFIG.library_url, url))
+            .query(&params)
+            .header("Authorization", &config::CONFIG.library_api_key)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match result {
+            Ok(v) => break v,
+            Err(err) if is_retryable(&err) && attempt < config::CONFIG.library_max_retries => {
+                attempt += 1;
+                log::warn!(
+                    "Library request failed, retrying (attempt {}/{}): url={:?} err={}",
+                    attempt,
+                    config::CONFIG.library_max_retries,
+                    url,
+                    err
+                );
+
+                let backoff_ms = RETRY_BASE_DELAY_MS * 2u64.pow(attempt - 1);
+                tokio::time::sleep(Duration::from_millis(backoff_ms)).await;
+            }
+            Err(err) => {
+                if is_retryable(&err) {
+                    CIRCUIT_BREAKER
+                        .lock()
+                        .unwrap()
+                        .record_failure(Instant::now());
+                }
+                return Err(ServiceError::Library(err));
+            }
+        }
+    };
+
+    CIRCUIT_BREAKER.lock().unwrap().record_success();
 
     match response.json::<T>().await {
         Ok(v) => Ok(v),
         Err(err) => {
             log::error!("Failed serialization: url={:?} err={:?}", url, err);
-            Err(Box::new(err))
+            Err(ServiceError::Library(err))
         }
     }
 }
 
+/// Check that the library API answers within `timeout`.
+/// Any response but a server error counts.
+pub async fn check_reachable(timeout: Duration) -> Result<(), String> {
+    if !CIRCUIT_BREAKER
+        .lock()
+        .unwrap()
+        .allow_request(Instant::now())
+    {
+        return Err("circuit breaker is open".to_string());
+    }
+
+    let response = CLIENT
+        .get(&config::CONFIG.library_url)
+        .header("Authorization", &config::CONFIG.library_api_key)
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if response.status().is_server_error() {
+        return Err(format!("unexpected status {}", response.status()));
+    }
+
+    Ok(())
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Book {
     pub id: u64,
@@ -73,49 +201,241 @@ pub struct Author {
     pub middle_name: Option<String>,
 }
 
-pub async fn get_author_books(
+/// Key of a cached page of a book listing.
+#[derive(Clone, Hash, PartialEq, Eq)]
+struct BooksPageKey {
+    kind: &'static str,
     id: u32,
     page: u32,
+    size: u32,
     allowed_langs: SmallVec<[SmartString; 3]>,
-) -> Result<Page<Book>, Box<dyn std::error::Error + Send + Sync>> {
-    let mut params = get_allowed_langs_params(allowed_langs);
+}
+
+fn build_cache<K, V>() -> Cache<K, V>
+where
+    K: std::hash::Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    Cache::builder()
+        .time_to_live(Duration::from_secs(config::CONFIG.library_cache_ttl_secs))
+        .max_capacity(4096)
+        .support_invalidation_closures()
+        .build()
+}
 
-    params.push(("page", page.to_string().into()));
-    params.push(("size", PAGE_SIZE.to_string().into()));
+/// Cached values keep the generations they were fetched at.
+type GenerationCache<K, V> = Cache<K, (Vec<u64>, V)>;
 
-    _make_request(format!("/api/v1/authors/{id}/books").as_str(), params).await
+static AUTHORS: Lazy<GenerationCache<u32, Author>> = Lazy::new(build_cache);
+static SEQUENCES: Lazy<GenerationCache<u32, Sequence>> = Lazy::new(build_cache);
+static BOOKS_PAGES: Lazy<GenerationCache<BooksPageKey, Page<Book>>> = Lazy::new(build_cache);
+
+/// Generation counter bumped to invalidate all caches.
+const ALL_GENERATION: &str = "library_cache";
+
+/// Generation counter bumped to invalidate the object `kind`/`id`.
+fn object_generation(kind: &str, id: u32) -> String {
+    format!("{ALL_GENERATION}:{kind}:{id}")
 }
 
-pub async fn get_translator_books(
+/// Return the cached value for `key` or fetch and cache it.
+///
+/// Values are cached only while the generation counters in `generations`
+/// are unchanged, so invalidations reach every instance.
+async fn get_cached<K, V, Fut>(
+    cache: &GenerationCache<K, V>,
+    cache_name: &'static str,
+    key: K,
+    generations: Vec<String>,
+    fetch: impl FnOnce() -> Fut,
+) -> Result<V, ServiceError>
+where
+    K: std::hash::Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Result<V, ServiceError>>,
+{
+    let mut names = vec![ALL_GENERATION.to_string()];
+    names.extend(generations);
+
+    // Without the generations a cached value might be stale.
+    let current = TASKS
+        .get_generations(&names)
+        .await
+        .map_err(|err| log::error!("Can't get library cache generations: {}", err))
+        .ok();
+
+    if let (Some(current), Some((cached, v))) = (&current, cache.get(&key).await) {
+        if cached == *current {
+            metrics::counter!("library_cache_requests_total", "cache" => cache_name, "result" => "hit")
+                .increment(1);
+            return Ok(v);
+        }
+    }
+
+    metrics::counter!("library_cache_requests_total", "cache" => cache_name, "result" => "miss")
+        .increment(1);
+
+    let value = fetch().await?;
+    if let Some(current) = current {
+        cache.insert(key, (current, value.clone())).await;
+    }
+
+    Ok(value)
+}
+
+async fn get_books_page(
+    kind: &'static str,
     id: u32,
     page: u32,
-    allowed_langs: SmallVec<[SmartString; 3]>,
-) -> Result<Page<Book>, Box<dyn std::error::Error + Send + Sync>> {
-    let mut params = get_allowed_langs_params(allowed_langs);
+    mut allowed_langs: SmallVec<[SmartString; 3]>,
+) -> Result<Page<Book>, ServiceError> {
+    allowed_langs.sort();
+
+    let size = config::CONFIG.library_page_size;
+    let key = BooksPageKey {
+        kind,
+        id,
+        page,
+        size,
+        allowed_langs: allowed_langs.clone(),
+    };
+
+    let generations = vec![object_generation(kind, id)];
+
+    get_cached(&BOOKS_PAGES, "books", key, generations, || async move {
+        let mut params = get_allowed_langs_params(allowed_langs);
 
-    params.push(("page", page.to_string().into()));
-    params.push(("size", PAGE_SIZE.to_string().into()));
+        params.push(("page", page.to_string().into()));
+        params.push(("size", size.to_string().into()));
 
-    _make_request(format!("/api/v1/translators/{id}/books").as_str(), params).await
+        _make_request(format!("/api/v1/{kind}/{id}/books").as_str(), params).await
+    })
+    .await
+}
+
+pub async fn get_author_books(
+    id: u32,
+    page: u32,
+    allowed_langs: SmallVec<[SmartString; 3]>,
+) -> Result<Page<Book>, ServiceError> {
+    get_books_page("authors", id, page, allowed_langs).await
+}
+
+pub async fn get_translator_books(
+    id: u32,
+    page: u32,
+    allowed_langs: SmallVec<[SmartString; 3]>,
+) -> Result<Page<Book>, ServiceError> {
+    get_books_page("translators", id, page, allowed_langs).await
 }
 
 pub async fn get_sequence_books(
     id: u32,
     page: u32,
     allowed_langs: SmallVec<[SmartString; 3]>,
-) -> Result<Page<Book>, Box<dyn std::error::Error + Send + Sync>> {
-    let mut params = get_allowed_langs_params(allowed_langs);
+) -> Result<Page<Book>, ServiceError> {
+    get_books_page("sequences", id, page, allowed_langs).await
+}
+
+pub async fn get_author(id: u32) -> Result<Author, ServiceError> {
+    // Translators are authors too.
+    let generations = vec![
+        object_generation("authors", id),
+        object_generation("translators", id),
+    ];
+
+    get_cached(&AUTHORS, "authors", id, generations, || async move {
+        _make_request(&format!("/api/v1/authors/{id}"), vec![]).await
+    })
+    .await
+}
 
-    params.push(("page", page.to_string().into()));
-    params.push(("size", PAGE_SIZE.to_string().into()));
+pub async fn get_sequence(id: u32) -> Result<Sequence, ServiceError> {
+    let generations = vec![object_generation("sequences", id)];
 
-    _make_request(format!("/api/v1/sequences/{id}/books").as_str(), params).await
+    get_cached(&SEQUENCES, "sequences", id, generations, || async move {
+        _make_request(&format!("/api/v1/sequences/{id}"), vec![]).await
+    })
+    .await
 }
 
-pub async fn get_author(id: u32) -> Result<Author, Box<dyn std::error::Error + Send + Sync>> {
-    _make_request(&format!("/api/v1/authors/{id}"), vec![]).await
+fn generation_ttl() -> Duration {
+    Duration::from_secs(config::CONFIG.library_cache_ttl_secs)
 }
 
-pub async fn get_sequence(id: u32) -> Result<Sequence, Box<dyn std::error::Error + Send + Sync>> {
-    _make_request(&format!("/api/v1/sequences/{id}"), vec![]).await
+/// Drop cached metadata and book listings of a single object
+/// on all instances.
+pub async fn invalidate_cache(object_type: ObjectType, id: u32) -> Result<(), TaskStoreError> {
+    let kind = match object_type {
+        ObjectType::Sequence => {
+            SEQUENCES.invalidate(&id).await;
+            "sequences"
+        }
+        ObjectType::Author => {
+            AUTHORS.invalidate(&id).await;
+            "authors"
+        }
+        ObjectType::Translator => {
+            AUTHORS.invalidate(&id).await;
+            "translators"
+        }
+    };
+
+    // Only fails if invalidation closures are not enabled on the cache.
+    let _ = BOOKS_PAGES.invalidate_entries_if(move |key, _| key.kind == kind && key.id == id);
+
+    TASKS
+        .bump_generation(&object_generation(kind, id), generation_ttl())
+        .await
+}
+
+/// Drop all cached library metadata and book listings on all instances.
+pub async fn invalidate_all_caches() -> Result<(), TaskStoreError> {
+    AUTHORS.invalidate_all();
+    SEQUENCES.invalidate_all();
+    BOOKS_PAGES.invalidate_all();
+
+    TASKS
+        .bump_generation(ALL_GENERATION, generation_ttl())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::CircuitBreaker;
+
+    #[test]
+    fn circuit_opens_after_threshold_and_recovers() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+
+        breaker.record_failure(now);
+        assert!(breaker.allow_request(now));
+
+        breaker.record_failure(now);
+        assert!(!breaker.allow_request(now));
+        assert!(!breaker.allow_request(now + Duration::from_secs(29)));
+
+        // Trial request after the cooldown.
+        assert!(breaker.allow_request(now + Duration::from_secs(30)));
+
+        breaker.record_success();
+        breaker.record_failure(now);
+        assert!(breaker.allow_request(now));
+    }
+
+    #[test]
+    fn failed_trial_reopens_circuit() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(1, Duration::from_secs(10));
+
+        breaker.record_failure(now);
+        let later = now + Duration::from_secs(10);
+        assert!(breaker.allow_request(later));
+
+        breaker.record_failure(later);
+        assert!(!breaker.allow_request(later + Duration::from_secs(5)));
+    }
 }
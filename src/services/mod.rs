@@ -1,5 +1,17 @@
+pub mod admin;
+pub mod book_cache;
 pub mod cache_client;
+pub mod crypto;
 pub mod downloader;
 pub mod library_client;
+pub mod rate_limits;
+pub mod readiness;
+pub mod shutdown;
+pub mod storage;
 pub mod task_creator;
+pub mod task_queue;
+pub mod task_store;
+pub mod tfcs_limiter;
 pub mod utils;
+pub mod validation;
+pub mod watchdog;
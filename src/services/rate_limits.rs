@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use tracing::log;
+
+use super::task_store::TASKS;
+
+/// Remember that TFCS throttled `user_id` for `retry_after_secs`.
+/// The throttle is shared by all instances through the task store.
+pub async fn set_throttled(user_id: Option<i64>, retry_after_secs: u64) {
+    let ttl = Duration::from_secs(retry_after_secs);
+
+    if let Err(err) = TASKS.set_throttled(user_id, ttl).await {
+        log::error!("Can't store throttle of user {:?}: {}", user_id, err);
+    }
+}
+
+/// Seconds left until `user_id` may call TFCS again, if it is throttled.
+pub async fn get_retry_after(user_id: Option<i64>) -> Option<u64> {
+    match TASKS.get_throttled(user_id).await {
+        Ok(left) => left.map(to_retry_after_secs),
+        Err(err) => {
+            log::error!("Can't get throttle of user {:?}: {}", user_id, err);
+            None
+        }
+    }
+}
+
+/// Round up so clients never retry a bit too early.
+fn to_retry_after_secs(left: Duration) -> u64 {
+    left.as_secs() + u64::from(left.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::to_retry_after_secs;
+
+    #[test]
+    fn retry_after_is_rounded_up() {
+        assert_eq!(to_retry_after_secs(Duration::from_millis(29_001)), 30);
+        assert_eq!(to_retry_after_secs(Duration::from_secs(30)), 30);
+        assert_eq!(to_retry_after_secs(Duration::from_millis(1)), 1);
+    }
+}
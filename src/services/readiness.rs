@@ -0,0 +1,111 @@
+use std::{future::Future, time::Duration};
+
+use crate::{
+    config,
+    structures::{Readiness, ReadinessCheck},
+};
+
+use super::{cache_client, library_client, storage, task_queue};
+
+async fn run_check(
+    timeout: Duration,
+    check: impl Future<Output = Result<(), String>>,
+) -> Result<(), String> {
+    tokio::time::timeout(timeout, check)
+        .await
+        .unwrap_or_else(|_| Err("timed out".to_string()))
+}
+
+/// `None` results are checks skipped on this instance.
+fn to_readiness(results: Vec<(&'static str, Option<Result<(), String>>)>) -> Readiness {
+    let ready = results
+        .iter()
+        .all(|(_, result)| result.as_ref().is_none_or(Result::is_ok));
+
+    let checks = results
+        .into_iter()
+        .map(|(name, result)| {
+            let check = ReadinessCheck {
+                ok: result.as_ref().is_none_or(Result::is_ok),
+                skipped: result.is_none(),
+                error: result.and_then(Result::err),
+            };
+            (name, check)
+        })
+        .collect();
+
+    Readiness { ready, checks }
+}
+
+/// Check everything the instance needs to serve and build archives.
+/// Checks run concurrently, each limited by `READY_CHECK_TIMEOUT_SECS`.
+/// Storage is only written by instances building archives.
+pub async fn check_readiness() -> Readiness {
+    let timeout = Duration::from_secs(config::CONFIG.ready_check_timeout_secs);
+    let builds_archives = config::CONFIG.run_mode.builds_archives();
+
+    let (library, tfcs, storage, queue) = tokio::join!(
+        run_check(timeout, library_client::check_reachable(timeout)),
+        run_check(timeout, cache_client::check_reachable(timeout)),
+        async {
+            if builds_archives {
+                Some(run_check(timeout, storage::check_writable()).await)
+            } else {
+                None
+            }
+        },
+        run_check(timeout, task_queue::check_saturation()),
+    );
+
+    to_readiness(vec![
+        ("library", Some(library)),
+        ("tfcs", Some(tfcs)),
+        ("storage", storage),
+        ("queue", Some(queue)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{run_check, to_readiness};
+
+    #[test]
+    fn any_failed_check_makes_instance_not_ready() {
+        let readiness = to_readiness(vec![("library", Some(Ok(()))), ("tfcs", Some(Ok(())))]);
+        assert!(readiness.ready);
+
+        let readiness = to_readiness(vec![
+            ("library", Some(Ok(()))),
+            ("tfcs", Some(Err("unexpected status 502".to_string()))),
+        ]);
+        assert!(!readiness.ready);
+        assert!(readiness.checks["library"].ok);
+        assert_eq!(
+            readiness.checks["tfcs"].error.as_deref(),
+            Some("unexpected status 502")
+        );
+    }
+
+    #[test]
+    fn skipped_check_is_ok() {
+        let readiness = to_readiness(vec![("library", Some(Ok(()))), ("storage", None)]);
+
+        assert!(readiness.ready);
+        assert!(readiness.checks["storage"].ok);
+        assert!(readiness.checks["storage"].skipped);
+        assert!(!readiness.checks["library"].skipped);
+    }
+
+    #[tokio::test]
+    async fn slow_check_times_out() {
+        let result = run_check(Duration::from_millis(10), async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        })
+        .await;
+
+        assert_eq!(result, Err("timed out".to_string()));
+    }
+}
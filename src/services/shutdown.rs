@@ -0,0 +1,26 @@
+use once_cell::sync::Lazy;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tracing::log;
+
+static SHUTDOWN: Lazy<CancellationToken> = Lazy::new(CancellationToken::new);
+
+/// Stop accepting new tasks, see `is_shutting_down`.
+pub fn start() {
+    SHUTDOWN.cancel();
+}
+
+/// New tasks are rejected and queued ones are left to other workers.
+pub fn is_shutting_down() -> bool {
+    SHUTDOWN.is_cancelled()
+}
+
+/// Wait for SIGTERM or SIGINT.
+pub async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Cannot handle SIGTERM");
+
+    tokio::select! {
+        _ = terminate.recv() => log::info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT"),
+    }
+}
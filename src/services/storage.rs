@@ -0,0 +1,343 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use once_cell::sync::Lazy;
+use s3::{creds::Credentials, error::S3Error, Bucket, Region};
+use tokio_util::io::ReaderStream;
+use tracing::log;
+
+use crate::config::{self, StorageBackend};
+
+use super::task_store::TASKS;
+
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// Directory of this instance's archives: `{STORAGE_DIR}/{INSTANCE_ID}`.
+///
+/// Archives are always built here, whatever the storage backend is.
+static INSTANCE_DIR: Lazy<PathBuf> =
+    Lazy::new(|| PathBuf::from(&config::CONFIG.storage_dir).join(&config::CONFIG.instance_id));
+
+pub type ArchiveStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// Place where completed archives are kept until their task is evicted.
+#[async_trait]
+pub trait ArchiveStorage: Send + Sync {
+    /// Store the archive built at `file_path` under `key`.
+    /// `file_path` is consumed.
+    async fn put(&self, key: &str, file_path: &Path) -> std::io::Result<()>;
+
+    /// Stream the archive, `None` if it doesn't exist.
+    async fn get(&self, key: &str) -> std::io::Result<Option<ArchiveStream>>;
+
+    /// Size of the stored archive, `None` if it doesn't exist.
+    async fn size(&self, key: &str) -> std::io::Result<Option<u64>>;
+
+    async fn remove(&self, key: &str) -> std::io::Result<()>;
+
+    /// Temporary URL to download the archive directly from the storage,
+    /// `None` if the backend can't provide one.
+    async fn presigned_url(&self, key: &str, expiry_secs: u32) -> std::io::Result<Option<String>>;
+}
+
+/// Keeps archives in a directory of the local filesystem.
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: PathBuf) -> Self {
+        LocalStorage { dir }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+}
+
+fn none_if_not_found<T>(result: std::io::Result<T>) -> std::io::Result<Option<T>> {
+    match result {
+        Ok(v) => Ok(Some(v)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[async_trait]
+impl ArchiveStorage for LocalStorage {
+    async fn put(&self, key: &str, file_path: &Path) -> std::io::Result<()> {
+        tokio::fs::rename(file_path, self.path(key)).await
+    }
+
+    async fn get(&self, key: &str) -> std::io::Result<Option<ArchiveStream>> {
+        let file = none_if_not_found(tokio::fs::File::open(self.path(key)).await)?;
+
+        Ok(file.map(|file| ReaderStream::new(file).boxed()))
+    }
+
+    async fn size(&self, key: &str) -> std::io::Result<Option<u64>> {
+        let metadata = none_if_not_found(tokio::fs::metadata(self.path(key)).await)?;
+
+        Ok(metadata.map(|metadata| metadata.len()))
+    }
+
+    async fn remove(&self, key: &str) -> std::io::Result<()> {
+        none_if_not_found(tokio::fs::remove_file(self.path(key)).await).map(|_| ())
+    }
+
+    async fn presigned_url(
+        &self,
+        _key: &str,
+        _expiry_secs: u32,
+    ) -> std::io::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// Keeps archives in an S3-compatible bucket, shared by all instances.
+pub struct S3Storage {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+impl S3Storage {
+    pub fn new(bucket: Box<Bucket>, prefix: String) -> Self {
+        S3Storage { bucket, prefix }
+    }
+
+    pub fn from_config() -> Result<Self, S3Error> {
+        let config = &config::CONFIG;
+
+        let region = if config.s3_endpoint.is_empty() {
+            config.s3_region.parse()?
+        } else {
+            Region::Custom {
+                region: config.s3_region.clone(),
+                endpoint: config.s3_endpoint.clone(),
+            }
+        };
+
+        let credentials = Credentials::new(
+            Some(&config.s3_access_key),
+            Some(&config.s3_secret_key),
+            None,
+            None,
+            None,
+        )?;
+
+        let mut bucket = Bucket::new(&config.s3_bucket, region, credentials)?;
+        if config.s3_path_style {
+            bucket = bucket.with_path_style();
+        }
+
+        Ok(S3Storage::new(bucket, config.s3_prefix.clone()))
+    }
+
+    fn path(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}/{}", self.prefix.trim_end_matches('/'), key)
+        }
+    }
+}
+
+fn is_not_found(err: &S3Error) -> bool {
+    matches!(err, S3Error::HttpFailWithBody(404, _))
+}
+
+fn s3_io_error(err: S3Error) -> std::io::Error {
+    match err {
+        S3Error::Io(err) => err,
+        err => std::io::Error::other(err),
+    }
+}
+
+#[async_trait]
+impl ArchiveStorage for S3Storage {
+    async fn put(&self, key: &str, file_path: &Path) -> std::io::Result<()> {
+        let mut file = tokio::fs::File::open(file_path).await?;
+
+        self.bucket
+            .put_object_stream(&mut file, self.path(key))
+            .await
+            .map_err(s3_io_error)?;
+
+        tokio::fs::remove_file(file_path).await
+    }
+
+    async fn get(&self, key: &str) -> std::io::Result<Option<ArchiveStream>> {
+        match self.bucket.get_object_stream(self.path(key)).await {
+            Ok(response) => Ok(Some(response.bytes.map_err(s3_io_error).boxed())),
+            Err(err) if is_not_found(&err) => Ok(None),
+            Err(err) => Err(s3_io_error(err)),
+        }
+    }
+
+    async fn size(&self, key: &str) -> std::io::Result<Option<u64>> {
+        match self.bucket.head_object(self.path(key)).await {
+            Ok((_, 404)) => Ok(None),
+            Ok((head, _)) => Ok(head.content_length.map(|v| v.max(0) as u64)),
+            Err(err) if is_not_found(&err) => Ok(None),
+            Err(err) => Err(s3_io_error(err)),
+        }
+    }
+
+    async fn remove(&self, key: &str) -> std::io::Result<()> {
+        match self.bucket.delete_object(self.path(key)).await {
+            Ok(_) => Ok(()),
+            Err(err) if is_not_found(&err) => Ok(()),
+            Err(err) => Err(s3_io_error(err)),
+        }
+    }
+
+    async fn presigned_url(&self, key: &str, expiry_secs: u32) -> std::io::Result<Option<String>> {
+        self.bucket
+            .presign_get(self.path(key), expiry_secs, None)
+            .await
+            .map(Some)
+            .map_err(s3_io_error)
+    }
+}
+
+/// Storage of completed archives selected by `STORAGE_BACKEND`.
+pub static STORAGE: Lazy<Box<dyn ArchiveStorage>> =
+    Lazy::new(|| match config::CONFIG.storage_backend {
+        StorageBackend::Local => Box::new(LocalStorage::new(INSTANCE_DIR.clone())),
+        StorageBackend::S3 => Box::new(
+            S3Storage::from_config().unwrap_or_else(|err| panic!("Cannot configure S3: {err}")),
+        ),
+    });
+
+/// Archives are built here and moved to `STORAGE` when complete.
+pub fn get_partial_path(key: &str) -> PathBuf {
+    INSTANCE_DIR.join(format!("{key}.{PARTIAL_EXTENSION}"))
+}
+
+/// Check that the storage has at least `MIN_FREE_SPACE` bytes available.
+pub fn has_free_space() -> bool {
+    match fs4::available_space(&*INSTANCE_DIR) {
+        Ok(available) => {
+            metrics::gauge!("storage_free_bytes").set(available as f64);
+            available >= config::CONFIG.min_free_space
+        }
+        Err(err) => {
+            // Don't block builds if the filesystem can't be queried.
+            log::warn!("Can't get free space of {:?}: {}", *INSTANCE_DIR, err);
+            true
+        }
+    }
+}
+
+/// Check that archives can be built here: the directory is writable and
+/// has at least `MIN_FREE_SPACE` bytes available.
+pub async fn check_writable() -> Result<(), String> {
+    let probe_path = INSTANCE_DIR.join(".ready");
+
+    tokio::fs::write(&probe_path, b"")
+        .await
+        .map_err(|err| format!("{:?} is not writable: {}", *INSTANCE_DIR, err))?;
+    let _ = tokio::fs::remove_file(&probe_path).await;
+
+    if !has_free_space() {
+        return Err("not enough free space".to_string());
+    }
+
+    Ok(())
+}
+
+/// Create the storage directory and delete partial builds and archives
+/// without a matching task, e.g. left by a previous run.
+///
+/// Only partial builds are removed when archives are kept in S3, as the
+/// bucket is shared with other instances.
+pub async fn cleanup_storage() -> std::io::Result<()> {
+    tokio::fs::create_dir_all(&*INSTANCE_DIR).await?;
+
+    let keep_archives = config::CONFIG.storage_backend != StorageBackend::Local;
+
+    let mut entries = tokio::fs::read_dir(&*INSTANCE_DIR).await?;
+    let mut removed: usize = 0;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+
+        let is_partial = path
+            .extension()
+            .is_some_and(|extension| extension == PARTIAL_EXTENSION);
+
+        let is_orphaned = match path.file_name().and_then(|name| name.to_str()) {
+            Some(_) if is_partial || keep_archives => false,
+            // Keep the archive if its task can't be checked.
+            Some(name) => !TASKS.contains(name).await.unwrap_or(true),
+            None => true,
+        };
+
+        if !is_partial && !is_orphaned {
+            continue;
+        }
+
+        match tokio::fs::remove_file(&path).await {
+            Ok(_) => removed += 1,
+            Err(err) => log::warn!("Can't remove {:?}: {}", path, err),
+        }
+    }
+
+    log::info!("Storage cleanup removed {} files", removed);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+
+    use super::{ArchiveStorage, LocalStorage, S3Storage};
+
+    async fn roundtrip(storage: &dyn ArchiveStorage, dir: &std::path::Path) {
+        let file_path = dir.join("archive.partial");
+        tokio::fs::write(&file_path, b"archive").await.unwrap();
+
+        storage.put("key", &file_path).await.unwrap();
+        assert!(!file_path.exists());
+
+        assert_eq!(storage.size("key").await.unwrap(), Some(7));
+
+        let stream = storage.get("key").await.unwrap().unwrap();
+        let chunks: Vec<_> = stream.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"archive");
+
+        storage.remove("key").await.unwrap();
+        assert_eq!(storage.size("key").await.unwrap(), None);
+        assert!(storage.get("key").await.unwrap().is_none());
+
+        // Removing a missing archive is not an error.
+        storage.remove("key").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_storage_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path().join("archives"));
+        tokio::fs::create_dir(dir.path().join("archives"))
+            .await
+            .unwrap();
+
+        roundtrip(&storage, dir.path()).await;
+        assert!(storage.presigned_url("key", 60).await.unwrap().is_none());
+    }
+
+    /// Needs an S3-compatible server, e.g. MinIO, configured with the `S3_*`
+    /// env variables and an existing `S3_BUCKET`.
+    #[tokio::test]
+    #[ignore]
+    async fn s3_storage_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = S3Storage::from_config().unwrap();
+
+        roundtrip(&storage, dir.path()).await;
+        assert!(storage.presigned_url("key", 60).await.unwrap().is_some());
+    }
+}
@@ -1,14 +1,35 @@
-use std::{fs::File, io::Write};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
+use futures::{stream, StreamExt, TryStreamExt};
+use once_cell::sync::Lazy;
 use smallvec::SmallVec;
 use smartstring::alias::String as SmartString;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio_util::task::TaskTracker;
 use tracing::log;
-use zip::write::FileOptions;
+use zip::{write::FileOptions, AesMode};
 
 use crate::{
-    services::{cache_client, downloader::download, utils::get_filename},
-    structures::{CreateTask, ObjectType, Task},
-    views::TASK_RESULTS,
+    config::{self, TaskStoreBackend},
+    errors::ServiceError,
+    services::{
+        downloader::download,
+        rate_limits,
+        storage::{get_partial_path, has_free_space, STORAGE},
+        task_queue::{report_queue_depth, QueuedTask, TASK_QUEUE},
+        task_store::{TaskStoreError, TASKS},
+        utils::get_filename,
+        watchdog,
+    },
+    structures::{
+        CreateTask, ErrorCode, ObjectType, SkipReason, SkippedBook, Task, TaskRequest, TaskStatus,
+    },
 };
 
 use super::{
@@ -16,120 +37,311 @@ use super::{
     utils::get_key,
 };
 
+/// Fetch all books of the object available in `file_format`.
+///
+/// Pages after the first one are fetched concurrently (at most
+/// `page_concurrency` at a time) and merged in page order, keeping
+/// the first occurrence of every book.
+///
+/// Also returns the total number of books in the library listing, which is
+/// stored on the task to detect books added after the archive was built.
 pub async fn get_books<Fut>(
     object_id: u32,
     allowed_langs: SmallVec<[SmartString; 3]>,
     books_getter: fn(id: u32, page: u32, allowed_langs: SmallVec<[SmartString; 3]>) -> Fut,
     file_format: SmartString,
-) -> Result<Vec<Book>, Box<dyn std::error::Error + Send + Sync>>
+    page_concurrency: usize,
+) -> Result<(Vec<Book>, u32), ServiceError>
 where
-    Fut: std::future::Future<Output = Result<Page<Book>, Box<dyn std::error::Error + Send + Sync>>>,
+    Fut: std::future::Future<Output = Result<Page<Book>, ServiceError>>,
 {
-    let mut result: Vec<Book> = vec![];
+    let first_page = books_getter(object_id, 1, allowed_langs.clone()).await?;
+
+    let library_total = first_page.total;
+    let page_count = first_page.pages;
+
+    let other_pages: Vec<Page<Book>> = stream::iter(2..=page_count)
+        .map(|page| books_getter(object_id, page, allowed_langs.clone()))
+        .buffered(page_concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    let mut seen_ids = HashSet::new();
+
+    let result = std::iter::once(first_page)
+        .chain(other_pages)
+        .flat_map(|page| page.items)
+        .filter(|book| seen_ids.insert(book.id))
+        .filter(|book| book.available_types.contains(&file_format.to_string()))
+        .collect();
+
+    Ok((result, library_total))
+}
+
+/// Fetch the current number of books in the library listing of the object.
+pub async fn get_library_total(data: &CreateTask) -> Result<u32, ServiceError> {
+    let langs = data.allowed_langs.clone();
+
+    let page = match data.object_type {
+        ObjectType::Sequence => get_sequence_books(data.object_id, 1, langs).await?,
+        ObjectType::Author => get_author_books(data.object_id, 1, langs).await?,
+        ObjectType::Translator => get_translator_books(data.object_id, 1, langs).await?,
+    };
 
-    let first_page = match books_getter(object_id, 1, allowed_langs.clone()).await {
+    Ok(page.total)
+}
+
+/// Check whether an active task was abandoned, e.g. its worker died:
+/// its status didn't change for `stall_timeout_secs` beyond a pause.
+///
+/// The watchdog only supervises builds of its own instance, this catches
+/// builds lost by the others.
+pub fn is_task_abandoned(task: &Task, now: u64, stall_timeout_secs: u64) -> bool {
+    let pause_secs = match task.status {
+        TaskStatus::Paused => task.retry_after_secs.unwrap_or(0),
+        _ => 0,
+    };
+
+    now.saturating_sub(task.updated_at) > pause_secs + stall_timeout_secs
+}
+
+/// Check whether a completed task has to be rebuilt: its archive is
+/// missing or truncated, or the library got new books since the build.
+pub async fn is_archive_stale(task: &Task, data: &CreateTask) -> bool {
+    match STORAGE.size(&task.id).await {
+        Ok(size) => {
+            if size != task.content_size {
+                log::warn!(
+                    "Archive {} is missing or has unexpected size, rebuilding",
+                    task.id
+                );
+                return true;
+            }
+        }
+        Err(err) => {
+            log::warn!("Archive {} is unavailable ({}), rebuilding", task.id, err);
+            return true;
+        }
+    };
+
+    let library_total = match get_library_total(data).await {
         Ok(v) => v,
-        Err(err) => return Err(err),
+        Err(err) => {
+            // Serve the existing archive if the library can't be checked.
+            log::warn!("Can't check library for task {}: {}", task.id, err);
+            return false;
+        }
     };
 
-    result.extend(first_page.items);
+    if task.library_total.is_some_and(|v| v != library_total) {
+        log::info!("Library changed for task {}, rebuilding", task.id);
+        return true;
+    }
 
-    let mut current_page = 2;
-    let page_count = first_page.pages;
+    false
+}
 
-    while current_page <= page_count {
-        let page = match books_getter(object_id, current_page, allowed_langs.clone()).await {
-            Ok(v) => v,
-            Err(err) => return Err(err),
-        };
-        result.extend(page.items);
+/// Save the task state, the build goes on if the store is unavailable.
+///
+/// Status setters only know the new state, so the request and creation
+/// time are carried over from the stored task.
+async fn store_task(mut task: Task) {
+    let key = task.id.clone();
+
+    match TASKS.get(&key).await {
+        Ok(Some(previous)) => {
+            task.request = previous.request;
+            task.created_at = previous.created_at;
+        }
+        Ok(None) => (),
+        Err(err) => log::error!("Can't get task {}: {}", key, err),
+    }
+
+    record_task_result(&task);
 
-        current_page += 1;
+    if let Err(err) = TASKS.insert(task).await {
+        log::error!("Can't store task {}: {}", key, err);
     }
+}
 
-    let result = result
-        .iter()
-        .filter(|book| book.available_types.contains(&file_format.to_string()))
-        .cloned()
-        .collect();
+/// Count finished tasks by the requested object type and format.
+fn record_task_result(task: &Task) {
+    let Some(request) = &task.request else {
+        return;
+    };
+
+    let object_type = request.object_type.as_str();
+    let file_format = request.file_format.to_string();
 
-    Ok(result)
+    match task.status {
+        TaskStatus::Complete => metrics::counter!(
+            "archive_tasks_completed_total",
+            "object_type" => object_type,
+            "file_format" => file_format
+        )
+        .increment(1),
+        TaskStatus::Failed | TaskStatus::RateLimited => metrics::counter!(
+            "archive_tasks_failed_total",
+            "object_type" => object_type,
+            "file_format" => file_format,
+            "error_code" => task.error_code.map_or("unknown", |v| v.as_str())
+        )
+        .increment(1),
+        _ => (),
+    }
 }
 
-pub async fn set_task_error(key: String, error_message: String) {
+pub async fn set_task_error(key: String, error_message: String, error_code: ErrorCode) {
     let task = Task {
-        id: key.clone(),
-        status: crate::structures::TaskStatus::Failed,
-        status_description: "Ошибка!".to_string(),
         error_message: Some(error_message),
-        result_filename: None,
-        content_size: None,
+        error_code: Some(error_code),
+        ..Task::new(key, TaskStatus::Failed, "Ошибка!".to_string())
     };
 
-    TASK_RESULTS.insert(key, task.clone()).await;
+    store_task(task).await;
 }
 
-pub async fn set_progress_description(key: String, description: String) {
+pub async fn set_task_rate_limited(key: String, user_id: Option<i64>, retry_after_secs: u64) {
+    rate_limits::set_throttled(user_id, retry_after_secs).await;
+
     let task = Task {
-        id: key.clone(),
-        status: crate::structures::TaskStatus::InProgress,
-        status_description: description,
-        error_message: None,
-        result_filename: None,
-        content_size: None,
+        error_message: Some("Rate limited!".to_string()),
+        error_code: Some(ErrorCode::RateLimited),
+        retry_after_secs: Some(retry_after_secs),
+        rate_limited_user_id: user_id,
+        ..Task::new(
+            key,
+            TaskStatus::RateLimited,
+            "Превышен лимит запросов, повторите позже".to_string(),
+        )
     };
 
-    TASK_RESULTS.insert(key, task.clone()).await;
+    store_task(task).await;
 }
 
+pub async fn set_task_paused(
+    key: String,
+    user_id: Option<i64>,
+    retry_after_secs: u64,
+    description: String,
+) {
+    rate_limits::set_throttled(user_id, retry_after_secs).await;
+    watchdog::beat_with_pause(&key, Duration::from_secs(retry_after_secs));
+
+    let task = Task {
+        retry_after_secs: Some(retry_after_secs),
+        rate_limited_user_id: user_id,
+        ..Task::new(key, TaskStatus::Paused, description)
+    };
+
+    store_task(task).await;
+}
+
+pub async fn set_progress_description(key: String, description: String) {
+    watchdog::beat(&key);
+
+    let task = Task::new(key, TaskStatus::InProgress, description);
+
+    store_task(task).await;
+}
+
+/// Build the archive in the partial file and move it to `STORAGE`.
+///
+/// Returns the archive size, the size of the books and the skipped books.
 pub async fn create_archive(
     key: String,
     books: Vec<Book>,
     file_format: SmartString,
     user_id: Option<i64>,
     normalized: bool,
-) -> Result<(File, u64), Box<dyn std::error::Error + Send + Sync>> {
-    let output_file = File::create(format!("/tmp/{}", key))?;
+    password: Option<String>,
+) -> Result<(u64, u64, Vec<SkippedBook>), ServiceError> {
+    let output_file = File::create(get_partial_path(&key))?;
     let mut archive = zip::ZipWriter::new(output_file);
 
-    let options: FileOptions<_> = FileOptions::default()
+    let mut options: FileOptions<_> = FileOptions::default()
         .compression_level(Some(9))
         .compression_method(zip::CompressionMethod::Deflated)
         .unix_permissions(0o755);
 
+    if let Some(password) = password.as_deref() {
+        options = options.with_aes_encryption(AesMode::Aes256, password);
+    }
+
     let books_count = books.len();
     let mut bytes_count: u64 = 0;
 
     let mut filenames: Vec<String> = vec![];
+    let mut skipped_books: Vec<SkippedBook> = vec![];
+
+    let mut pauses: u32 = 0;
 
     for (index, book) in books.iter().enumerate() {
-        let (mut tmp_file, filename) =
+        watchdog::beat(&key);
+
+        // On TFCS throttling keep the books written so far and park the task
+        // until the limit resets, then continue from the same book.
+        let download_result = loop {
             match download(book.id, file_format.clone(), user_id, normalized).await {
-                Ok(v) => v,
-                Err(err) => {
-                    // Propagate rate limit errors immediately — do not silently skip.
-                    // Other errors (network, missing file) are tolerated and skipped.
-                    if err.downcast_ref::<cache_client::RateLimitError>().is_some() {
-                        return Err(err);
-                    }
-                    log::warn!("Skipping book {} due to error: {}", book.id, err);
-                    continue;
+                Err(ServiceError::RateLimited(err))
+                    if pauses < config::CONFIG.rate_limit_max_pauses =>
+                {
+                    pauses += 1;
+                    log::warn!(
+                        "Pausing task {} at book {}/{}: {}",
+                        key,
+                        index + 1,
+                        books_count,
+                        err
+                    );
+
+                    set_task_paused(
+                        key.clone(),
+                        user_id,
+                        err.retry_after_secs,
+                        format!("Пауза из-за лимита запросов: {}/{}", index, books_count),
+                    )
+                    .await;
+
+                    tokio::time::sleep(Duration::from_secs(err.retry_after_secs)).await;
                 }
-            };
+                result => break result,
+            }
+        };
+
+        let (mut tmp_file, filename) = match download_result {
+            Ok(v) => v,
+            Err(err) => {
+                // Propagate rate limit errors immediately — do not silently skip.
+                // Other errors (network, missing file) are tolerated and skipped.
+                if matches!(err, ServiceError::RateLimited(_)) {
+                    return Err(err);
+                }
+                log::warn!("Skipping book {} due to error: {}", book.id, err);
+
+                let reason = match err {
+                    ServiceError::Validation(_) => SkipReason::InvalidFile,
+                    _ => SkipReason::DownloadFailed,
+                };
+                skipped_books.push(SkippedBook {
+                    book_id: book.id,
+                    reason,
+                });
+                continue;
+            }
+        };
 
         if filenames.contains(&filename) {
+            skipped_books.push(SkippedBook {
+                book_id: book.id,
+                reason: SkipReason::DuplicateFilename,
+            });
             continue;
         }
 
-        match archive.start_file::<std::string::String, ()>(filename.clone(), options) {
-            Ok(_) => (),
-            Err(err) => return Err(Box::new(err)),
-        };
+        archive.start_file::<std::string::String, ()>(filename.clone(), options)?;
 
-        match std::io::copy(&mut tmp_file, &mut archive) {
-            Ok(file_bytes_count) => bytes_count += file_bytes_count,
-            Err(err) => return Err(Box::new(err)),
-        };
+        bytes_count += std::io::copy(&mut tmp_file, &mut archive)?;
 
         filenames.push(filename);
 
@@ -140,17 +352,70 @@ pub async fn create_archive(
         .await;
     }
 
-    let mut archive_result = match archive.finish() {
-        Ok(v) => v,
-        Err(err) => return Err(Box::new(err)),
-    };
+    let mut archive_result = archive.finish()?;
 
     archive_result.flush()?;
+    let archive_size = archive_result.metadata()?.len();
+    drop(archive_result);
 
-    Ok((archive_result, bytes_count))
+    for skipped_book in &skipped_books {
+        metrics::counter!("archive_skipped_books_total", "reason" => skipped_book.reason.as_str())
+            .increment(1);
+    }
+
+    set_progress_description(key.clone(), "Загрузка архива...".to_string()).await;
+    STORAGE.put(&key, &get_partial_path(&key)).await?;
+
+    Ok((archive_size, bytes_count, skipped_books))
+}
+
+async fn handle_archive_error(key: String, user_id: Option<i64>, err: ServiceError) {
+    match err {
+        ServiceError::RateLimited(err) => {
+            set_task_rate_limited(key, user_id, err.retry_after_secs).await;
+            log::warn!("{}", err);
+        }
+        err => {
+            set_task_error(key, "Failed downloading books!".to_string(), err.code()).await;
+            log::error!("{}", err);
+        }
+    }
 }
 
+/// Build the archive, failing the task if it runs past `TASK_TIMEOUT_SECS`.
 pub async fn create_archive_task(key: String, data: CreateTask) {
+    let deadline = Duration::from_secs(config::CONFIG.task_timeout_secs);
+    let started = Instant::now();
+
+    let object_type = data.object_type.as_str();
+    let file_format = data.file_format.to_string();
+
+    let result = tokio::time::timeout(deadline, build_archive(key.clone(), data)).await;
+
+    metrics::histogram!(
+        "archive_build_duration_seconds",
+        "object_type" => object_type,
+        "file_format" => file_format
+    )
+    .record(started.elapsed().as_secs_f64());
+
+    if result.is_err() {
+        log::error!("Task {} timed out after {:?}", key, deadline);
+
+        let _ = tokio::fs::remove_file(get_partial_path(&key)).await;
+        set_task_error(
+            key.clone(),
+            "Task timed out!".to_string(),
+            ErrorCode::Timeout,
+        )
+        .await;
+    }
+}
+
+async fn build_archive(key: String, data: CreateTask) {
+    let object_type = data.object_type.as_str();
+    let file_format = data.file_format.to_string();
+
     let books = match data.object_type {
         ObjectType::Sequence => {
             get_books(
@@ -158,6 +423,7 @@ pub async fn create_archive_task(key: String, data: CreateTask) {
                 data.allowed_langs.clone(),
                 get_sequence_books,
                 data.file_format.clone(),
+                config::CONFIG.library_page_concurrency,
             )
             .await
         }
@@ -167,6 +433,7 @@ pub async fn create_archive_task(key: String, data: CreateTask) {
                 data.allowed_langs.clone(),
                 get_author_books,
                 data.file_format.clone(),
+                config::CONFIG.library_page_concurrency,
             )
             .await
         }
@@ -176,6 +443,7 @@ pub async fn create_archive_task(key: String, data: CreateTask) {
                 data.allowed_langs.clone(),
                 get_translator_books,
                 data.file_format.clone(),
+                config::CONFIG.library_page_concurrency,
             )
             .await
         }
@@ -183,17 +451,26 @@ pub async fn create_archive_task(key: String, data: CreateTask) {
 
     set_progress_description(key.clone(), "Получение списка книг...".to_string()).await;
 
-    let books = match books {
+    let (books, library_total) = match books {
         Ok(v) => v,
         Err(err) => {
-            set_task_error(key.clone(), "Failed getting books!".to_string()).await;
+            let error_message = match err {
+                ServiceError::LibraryUnavailable => "Library is unavailable!",
+                _ => "Failed getting books!",
+            };
+            set_task_error(key.clone(), error_message.to_string(), err.code()).await;
             log::error!("{}", err);
             return;
         }
     };
 
     if books.is_empty() {
-        set_task_error(key.clone(), "No books!".to_string()).await;
+        set_task_error(
+            key.clone(),
+            "No books!".to_string(),
+            ServiceError::NoBooks.code(),
+        )
+        .await;
         return;
     }
 
@@ -207,60 +484,351 @@ pub async fn create_archive_task(key: String, data: CreateTask) {
     {
         Ok(v) => v,
         Err(err) => {
-            set_task_error(key.clone(), "Can't get archive name!".to_string()).await;
+            set_task_error(
+                key.clone(),
+                "Can't get archive name!".to_string(),
+                err.code(),
+            )
+            .await;
             log::error!("{}", err);
             return;
         }
     };
 
+    if !has_free_space() {
+        // Let pending evictions free the disk before giving up.
+        TASKS.run_pending_tasks().await;
+
+        if !has_free_space() {
+            set_task_error(
+                key.clone(),
+                "Not enough disk space!".to_string(),
+                ErrorCode::InsufficientStorage,
+            )
+            .await;
+            return;
+        }
+    }
+
     set_progress_description(key.clone(), "Сборка архива...".to_string()).await;
 
-    let (archive_result, _inside_content_size) = match create_archive(
+    let books_count = books.len();
+
+    let (content_size, _inside_content_size, skipped_books) = match create_archive(
         key.clone(),
         books,
         data.file_format,
         data.user_id,
         data.normalized,
+        data.password,
     )
     .await
     {
         Ok(v) => v,
         Err(err) => {
-            set_task_error(key.clone(), "Failed downloading books!".to_string()).await;
-            log::error!("{}", err);
-            return;
+            let _ = tokio::fs::remove_file(get_partial_path(&key)).await;
+            return handle_archive_error(key, data.user_id, err).await;
         }
     };
 
-    set_progress_description(key.clone(), "Загрузка архива...".to_string()).await;
-
     let task = Task {
-        id: key.clone(),
-        status: crate::structures::TaskStatus::Complete,
-        status_description: "Архив готов! Ожидайте файл".to_string(),
-        error_message: None,
         result_filename: Some(final_filename),
-        content_size: Some(archive_result.metadata().unwrap().len()),
+        content_size: Some(content_size),
+        skipped_books,
+        library_total: Some(library_total),
+        ..Task::new(
+            key,
+            TaskStatus::Complete,
+            "Архив готов! Ожидайте файл".to_string(),
+        )
     };
 
-    TASK_RESULTS.insert(key.clone(), task.clone()).await;
+    metrics::histogram!(
+        "archive_books",
+        "object_type" => object_type,
+        "file_format" => file_format.clone()
+    )
+    .record((books_count - task.skipped_books.len()) as f64);
+    metrics::histogram!(
+        "archive_bytes",
+        "object_type" => object_type,
+        "file_format" => file_format
+    )
+    .record(content_size as f64);
+
+    store_task(task).await;
 }
 
-pub async fn create_task(data: CreateTask) -> Task {
+/// Queue the task for a worker unless it is already being built.
+pub async fn create_task(data: CreateTask) -> Result<Task, TaskStoreError> {
     let key = get_key(data.clone());
 
     let task = Task {
-        id: key.clone(),
-        status: crate::structures::TaskStatus::InProgress,
-        status_description: "Подготовка".to_string(),
-        error_message: None,
-        result_filename: None,
-        content_size: None,
+        request: Some(TaskRequest::from(&data)),
+        ..Task::new(
+            key.clone(),
+            TaskStatus::InProgress,
+            "Подготовка".to_string(),
+        )
+    };
+
+    // Covers the wait in the queue too; an expired lock only lets a
+    // duplicate build through.
+    let lock_ttl = Duration::from_secs(config::CONFIG.task_timeout_secs);
+    let lock_token = format!("{}:{:016x}", config::CONFIG.instance_id, fastrand::u64(..));
+
+    if !TASKS.try_lock(&key, &lock_token, lock_ttl).await? {
+        return Ok(TASKS.get(&key).await?.unwrap_or(task));
+    }
+
+    TASKS.insert(task.clone()).await?;
+
+    let object_type = data.object_type.as_str();
+    let file_format = data.file_format.to_string();
+
+    let queued_task = QueuedTask::new(key.clone(), data, lock_token.clone());
+    if let Err(err) = TASK_QUEUE.push(queued_task).await {
+        // Let the next request retry.
+        let _ = TASKS.unlock(&key, &lock_token).await;
+        return Err(err);
+    }
+
+    metrics::counter!(
+        "archive_tasks_created_total",
+        "object_type" => object_type,
+        "file_format" => file_format
+    )
+    .increment(1);
+    report_queue_depth().await;
+
+    Ok(task)
+}
+
+/// Remember who requested the task. Requesters are kept apart from the task
+/// key so that one archive is built once and shared by everyone asking for
+/// the same content.
+pub async fn track_requester(key: &str, user_id: Option<i64>, reused: bool) {
+    tracing::info!(
+        task_id = key,
+        user_id = user_id
+            .map(|v| v.to_string())
+            .as_deref()
+            .unwrap_or("anonymous"),
+        reused,
+        "Archive requested"
+    );
+
+    let Some(user_id) = user_id else {
+        return;
+    };
+
+    if let Err(err) = TASKS.add_requester(key, user_id).await {
+        log::error!("Can't track requester of task {}: {}", key, err);
+    }
+}
+
+/// Builds running in this instance.
+static BUILDS: Lazy<TaskTracker> = Lazy::new(TaskTracker::new);
+
+/// Queued tasks of the running builds, by task key.
+static RUNNING_BUILDS: Lazy<Mutex<HashMap<String, QueuedTask>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Build the queued task in the background, holding the worker's `permit`
+/// until the build ends.
+pub fn start_build(task: QueuedTask, permit: OwnedSemaphorePermit) {
+    let key = task.key.clone();
+    let data = task.to_data();
+
+    RUNNING_BUILDS.lock().unwrap().insert(key.clone(), task);
+
+    let handle = BUILDS.spawn({
+        let key = key.clone();
+
+        async move {
+            match data {
+                Ok(data) => {
+                    watchdog::scope(key.clone(), create_archive_task(key.clone(), data)).await
+                }
+                Err(err) => {
+                    log::error!("Can't get password of task {}: {}", key, err);
+                    set_task_error(
+                        key.clone(),
+                        "Can't decrypt the archive password!".to_string(),
+                        ErrorCode::PasswordUnavailable,
+                    )
+                    .await;
+                }
+            }
+            release_build(&key).await;
+            drop(permit);
+        }
+    });
+    watchdog::register(key, handle.abort_handle());
+}
+
+/// Forget the build of the task and release its lock.
+/// Called when the build ends or is aborted.
+pub async fn release_build(key: &str) {
+    watchdog::unregister(key);
+
+    let task = RUNNING_BUILDS.lock().unwrap().remove(key);
+
+    let Some(task) = task else {
+        return;
     };
 
-    TASK_RESULTS.insert(key.clone(), task.clone()).await;
+    if let Err(err) = TASKS.unlock(key, &task.lock_token).await {
+        log::error!("Can't unlock task {}: {}", key, err);
+    }
+}
+
+/// Hand the task whose build didn't finish before shutdown to another
+/// worker, or fail it if the queue isn't shared.
+pub async fn interrupt_build(task: QueuedTask) {
+    let key = task.key.clone();
+    let _ = tokio::fs::remove_file(get_partial_path(&key)).await;
+
+    if config::CONFIG.task_store_backend == TaskStoreBackend::Redis {
+        // The lock travels with the task.
+        set_progress_description(key.clone(), "Подготовка".to_string()).await;
+
+        match TASK_QUEUE.push(task.clone()).await {
+            Ok(_) => {
+                log::info!("Task {} requeued on shutdown", key);
+                return;
+            }
+            Err(err) => log::error!("Can't requeue task {}: {}", key, err),
+        }
+    }
+
+    set_task_error(
+        key.clone(),
+        "Task interrupted!".to_string(),
+        ErrorCode::Interrupted,
+    )
+    .await;
+
+    if let Err(err) = TASKS.unlock(&key, &task.lock_token).await {
+        log::error!("Can't unlock task {}: {}", key, err);
+    }
+}
+
+/// Wait up to `grace_period` for the running builds, then abort and
+/// interrupt the rest.
+pub async fn drain_builds(grace_period: Duration) {
+    BUILDS.close();
+
+    log::info!("Waiting for {} running builds", BUILDS.len());
+    if tokio::time::timeout(grace_period, BUILDS.wait())
+        .await
+        .is_ok()
+    {
+        return;
+    }
+
+    let interrupted: Vec<QueuedTask> = RUNNING_BUILDS
+        .lock()
+        .unwrap()
+        .drain()
+        .map(|(_, task)| task)
+        .collect();
+
+    for task in &interrupted {
+        watchdog::abort(&task.key);
+    }
 
-    tokio::spawn(create_archive_task(key, data));
+    // Aborted builds stop at their next await point.
+    let _ = tokio::time::timeout(Duration::from_secs(5), BUILDS.wait()).await;
 
-    task
+    for task in interrupted {
+        // The build may have finished before it was aborted, its stored
+        // result must stay. It wasn't released, so only the lock is left.
+        match TASKS.get(&task.key).await {
+            Ok(Some(stored)) if !stored.status.is_active() => {
+                if let Err(err) = TASKS.unlock(&task.key, &task.lock_token).await {
+                    log::error!("Can't unlock task {}: {}", task.key, err);
+                }
+                continue;
+            }
+            Ok(_) => {}
+            Err(err) => log::error!("Can't get task {}: {}", task.key, err),
+        }
+
+        log::warn!("Interrupting task {}", task.key);
+        interrupt_build(task).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smallvec::{smallvec, SmallVec};
+    use smartstring::alias::String as SmartString;
+
+    use super::{get_books, is_task_abandoned};
+    use crate::{
+        errors::ServiceError,
+        services::library_client::{Book, Page},
+        structures::{Task, TaskStatus},
+    };
+
+    fn book(id: u64, file_type: &str) -> Book {
+        Book {
+            id,
+            available_types: smallvec![file_type.to_string()],
+        }
+    }
+
+    async fn books_getter(
+        _id: u32,
+        page: u32,
+        _allowed_langs: SmallVec<[SmartString; 3]>,
+    ) -> Result<Page<Book>, ServiceError> {
+        // Later pages answer faster to check that the order is kept.
+        tokio::time::sleep(std::time::Duration::from_millis(u64::from(10 - page))).await;
+
+        let items = match page {
+            1 => vec![book(1, "fb2"), book(2, "epub")],
+            // Book 1 moved to the next page while paginating.
+            2 => vec![book(1, "fb2"), book(3, "fb2")],
+            _ => vec![book(u64::from(page) + 10, "fb2")],
+        };
+
+        Ok(Page {
+            items,
+            total: 6,
+            page,
+            size: 2,
+            pages: 4,
+        })
+    }
+
+    #[test]
+    fn task_is_abandoned_after_stall_timeout() {
+        let timeout = 600;
+        let now = timeout + 1000;
+
+        let task_in_progress = Task::fixture("task", TaskStatus::InProgress, 1000);
+        assert!(!is_task_abandoned(&task_in_progress, now, timeout));
+        assert!(is_task_abandoned(&task_in_progress, now + 1, timeout));
+
+        // Paused tasks don't change their status while waiting.
+        let paused = Task {
+            retry_after_secs: Some(300),
+            ..Task::fixture("task", TaskStatus::Paused, 1000)
+        };
+        assert!(!is_task_abandoned(&paused, now + 300, timeout));
+        assert!(is_task_abandoned(&paused, now + 301, timeout));
+    }
+
+    #[tokio::test]
+    async fn get_books_merges_pages_in_order() {
+        let (books, total) = get_books(1, smallvec![], books_getter, "fb2".into(), 4)
+            .await
+            .unwrap();
+
+        let ids: Vec<u64> = books.iter().map(|book| book.id).collect();
+        assert_eq!(ids, vec![1, 3, 13, 14]);
+        assert_eq!(total, 6);
+    }
 }
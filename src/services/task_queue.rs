@@ -0,0 +1,357 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex, OnceCell, Semaphore};
+use tracing::log;
+
+use crate::{
+    config::{self, TaskStoreBackend},
+    structures::CreateTask,
+};
+
+use super::{
+    crypto::{TaskSecret, TASK_SECRET},
+    shutdown,
+    task_creator::{interrupt_build, start_build},
+    task_store::TaskStoreError,
+};
+
+/// How long a worker waits for a task before polling again.
+const POP_TIMEOUT_SECS: u64 = 5;
+
+/// Task waiting for a worker to build it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QueuedTask {
+    pub key: String,
+    pub data: CreateTask,
+    /// `CreateTask` never serializes the password, but the worker needs it.
+    /// Encrypted with `TASK_SECRET`, as the queue may be shared in Redis.
+    pub encrypted_password: Option<String>,
+    /// Token of the build lock taken when the task was queued.
+    pub lock_token: String,
+}
+
+impl QueuedTask {
+    pub fn new(key: String, data: CreateTask, lock_token: String) -> Self {
+        Self::with_secret(key, data, lock_token, &TASK_SECRET)
+    }
+
+    /// `secret` is only loaded for tasks with a password.
+    fn with_secret(
+        key: String,
+        data: CreateTask,
+        lock_token: String,
+        secret: &Lazy<TaskSecret>,
+    ) -> Self {
+        QueuedTask {
+            key,
+            encrypted_password: data
+                .password
+                .as_deref()
+                .map(|password| secret.encrypt_password(password)),
+            data,
+            lock_token,
+        }
+    }
+
+    /// Task data with the decrypted password.
+    pub fn to_data(&self) -> Result<CreateTask, String> {
+        self.to_data_with_secret(&TASK_SECRET)
+    }
+
+    fn to_data_with_secret(&self, secret: &Lazy<TaskSecret>) -> Result<CreateTask, String> {
+        let password = self
+            .encrypted_password
+            .as_deref()
+            .map(|value| secret.decrypt_password(value))
+            .transpose()?;
+
+        Ok(CreateTask {
+            password,
+            ..self.data.clone()
+        })
+    }
+}
+
+/// Tasks accepted by the API and waiting to be built.
+#[async_trait]
+pub trait TaskQueue: Send + Sync {
+    async fn push(&self, task: QueuedTask) -> Result<(), TaskStoreError>;
+
+    /// Take the next task, `None` if nothing arrives for a while.
+    async fn pop(&self) -> Result<Option<QueuedTask>, TaskStoreError>;
+
+    /// Number of tasks waiting for a worker.
+    async fn depth(&self) -> Result<usize, TaskStoreError>;
+}
+
+/// Process-local queue, builds run in the instance accepting the tasks.
+pub struct MemoryTaskQueue {
+    sender: mpsc::UnboundedSender<QueuedTask>,
+    receiver: Mutex<mpsc::UnboundedReceiver<QueuedTask>>,
+    /// The receiver is locked while waiting, so its length isn't available.
+    len: AtomicUsize,
+}
+
+impl MemoryTaskQueue {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        MemoryTaskQueue {
+            sender,
+            receiver: Mutex::new(receiver),
+            len: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Default for MemoryTaskQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl TaskQueue for MemoryTaskQueue {
+    async fn push(&self, task: QueuedTask) -> Result<(), TaskStoreError> {
+        // The receiver lives as long as the queue.
+        self.len.fetch_add(1, Ordering::Relaxed);
+        let _ = self.sender.send(task);
+        Ok(())
+    }
+
+    async fn pop(&self) -> Result<Option<QueuedTask>, TaskStoreError> {
+        let mut receiver = self.receiver.lock().await;
+
+        let task = tokio::time::timeout(Duration::from_secs(POP_TIMEOUT_SECS), receiver.recv())
+            .await
+            .ok()
+            .flatten();
+
+        if task.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        Ok(task)
+    }
+
+    async fn depth(&self) -> Result<usize, TaskStoreError> {
+        Ok(self.len.load(Ordering::Relaxed))
+    }
+}
+
+/// Redis list shared by all instances.
+pub struct RedisTaskQueue {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+    /// `BRPOP` holds its connection, so pushes go through the other one.
+    blocking_connection: OnceCell<ConnectionManager>,
+    key: String,
+}
+
+impl RedisTaskQueue {
+    pub fn new(url: &str, prefix: &str) -> Result<Self, TaskStoreError> {
+        Ok(RedisTaskQueue {
+            client: redis::Client::open(url)?,
+            connection: OnceCell::new(),
+            blocking_connection: OnceCell::new(),
+            key: format!("{prefix}:queue"),
+        })
+    }
+
+    async fn connect(
+        &self,
+        cell: &OnceCell<ConnectionManager>,
+    ) -> Result<ConnectionManager, TaskStoreError> {
+        let connection = cell
+            .get_or_try_init(|| self.client.get_connection_manager())
+            .await?;
+
+        Ok(connection.clone())
+    }
+}
+
+#[async_trait]
+impl TaskQueue for RedisTaskQueue {
+    async fn push(&self, task: QueuedTask) -> Result<(), TaskStoreError> {
+        let value = serde_json::to_string(&task)?;
+
+        redis::cmd("LPUSH")
+            .arg(&self.key)
+            .arg(value)
+            .query_async::<()>(&mut self.connect(&self.connection).await?)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn pop(&self) -> Result<Option<QueuedTask>, TaskStoreError> {
+        let value: Option<(String, String)> = redis::cmd("BRPOP")
+            .arg(&self.key)
+            .arg(POP_TIMEOUT_SECS)
+            .query_async(&mut self.connect(&self.blocking_connection).await?)
+            .await?;
+
+        match value {
+            Some((_, value)) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn depth(&self) -> Result<usize, TaskStoreError> {
+        let len: usize = redis::cmd("LLEN")
+            .arg(&self.key)
+            .query_async(&mut self.connect(&self.connection).await?)
+            .await?;
+
+        Ok(len)
+    }
+}
+
+/// Task queue selected by `TASK_STORE_BACKEND`.
+pub static TASK_QUEUE: Lazy<Box<dyn TaskQueue>> =
+    Lazy::new(|| match config::CONFIG.task_store_backend {
+        TaskStoreBackend::Memory => Box::new(MemoryTaskQueue::new()),
+        TaskStoreBackend::Redis => Box::new(
+            RedisTaskQueue::new(&config::CONFIG.redis_url, &config::CONFIG.redis_key_prefix)
+                .unwrap_or_else(|err| panic!("Cannot configure Redis: {err}")),
+        ),
+    });
+
+/// Update the `task_queue_depth` gauge.
+pub async fn report_queue_depth() {
+    match TASK_QUEUE.depth().await {
+        Ok(len) => metrics::gauge!("task_queue_depth").set(len as f64),
+        Err(err) => log::warn!("Can't get task queue length: {}", err),
+    }
+}
+
+fn check_depth(depth: usize, max_depth: usize) -> Result<(), String> {
+    if depth > max_depth {
+        return Err(format!("{depth} tasks are queued, limit is {max_depth}"));
+    }
+
+    Ok(())
+}
+
+/// Check that the workers keep up: no more than `READY_MAX_QUEUE_DEPTH`
+/// tasks are waiting.
+pub async fn check_saturation() -> Result<(), String> {
+    let depth = TASK_QUEUE.depth().await.map_err(|err| err.to_string())?;
+
+    check_depth(depth, config::CONFIG.ready_max_queue_depth)
+}
+
+/// Build queued tasks, at most `WORKER_CONCURRENCY` at a time, until
+/// the shutdown starts.
+pub async fn run_worker() {
+    let semaphore = Arc::new(Semaphore::new(config::CONFIG.worker_concurrency.max(1)));
+
+    while !shutdown::is_shutting_down() {
+        // Leave tasks to other workers while all builders are busy.
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+
+        let result = TASK_QUEUE.pop().await;
+        report_queue_depth().await;
+
+        let task = match result {
+            Ok(Some(v)) => v,
+            Ok(None) => continue,
+            Err(err) => {
+                log::error!("Can't get queued task: {}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        if shutdown::is_shutting_down() {
+            interrupt_build(task).await;
+            break;
+        }
+
+        start_build(task, permit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use once_cell::sync::Lazy;
+
+    use super::{check_depth, MemoryTaskQueue, QueuedTask, TaskQueue};
+    use crate::{services::crypto::TaskSecret, structures::CreateTask};
+
+    static TEST_SECRET: Lazy<TaskSecret> = Lazy::new(|| TaskSecret::new(b"test"));
+
+    fn data() -> CreateTask {
+        CreateTask {
+            password: Some("secret".to_string()),
+            ..CreateTask::fixture()
+        }
+    }
+
+    #[test]
+    fn queued_task_keeps_password_encrypted() {
+        let task =
+            QueuedTask::with_secret("key".to_string(), data(), "token".to_string(), &TEST_SECRET);
+
+        let value = serde_json::to_string(&task).unwrap();
+        assert!(!value.contains("secret"), "got: {value}");
+
+        let task: QueuedTask = serde_json::from_str(&value).unwrap();
+
+        assert_eq!(task.key, "key");
+        assert_eq!(task.lock_token, "token");
+        assert_eq!(
+            task.to_data_with_secret(&TEST_SECRET)
+                .unwrap()
+                .password
+                .as_deref(),
+            Some("secret")
+        );
+    }
+
+    #[test]
+    fn queue_is_saturated_above_max_depth() {
+        assert!(check_depth(0, 0).is_ok());
+        assert!(check_depth(10, 10).is_ok());
+        assert!(check_depth(11, 10).is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_queue_is_fifo() {
+        let queue = MemoryTaskQueue::new();
+        let data = CreateTask::fixture;
+
+        queue
+            .push(QueuedTask::new(
+                "first".to_string(),
+                data(),
+                "token".to_string(),
+            ))
+            .await
+            .unwrap();
+        queue
+            .push(QueuedTask::new(
+                "second".to_string(),
+                data(),
+                "token".to_string(),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(queue.depth().await.unwrap(), 2);
+
+        assert_eq!(queue.pop().await.unwrap().unwrap().key, "first");
+        assert_eq!(queue.pop().await.unwrap().unwrap().key, "second");
+        assert_eq!(queue.depth().await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,727 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use moka::{future::Cache, notification::RemovalCause, policy::EvictionPolicy};
+use once_cell::sync::Lazy;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+use tokio::sync::OnceCell;
+use tracing::log;
+
+use crate::{
+    config::{self, TaskStoreBackend},
+    structures::Task,
+};
+
+use super::storage::STORAGE;
+
+/// Tasks are dropped after this time without requests.
+pub const TASK_TTL: Duration = Duration::from_secs(3 * 60 * 60);
+
+#[derive(Debug)]
+pub enum TaskStoreError {
+    Redis(redis::RedisError),
+    Serialization(serde_json::Error),
+}
+
+impl fmt::Display for TaskStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TaskStoreError::Redis(e) => write!(f, "redis error: {e}"),
+            TaskStoreError::Serialization(e) => write!(f, "serialization error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TaskStoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TaskStoreError::Redis(e) => Some(e),
+            TaskStoreError::Serialization(e) => Some(e),
+        }
+    }
+}
+
+impl From<redis::RedisError> for TaskStoreError {
+    fn from(e: redis::RedisError) -> Self {
+        TaskStoreError::Redis(e)
+    }
+}
+
+impl From<serde_json::Error> for TaskStoreError {
+    fn from(e: serde_json::Error) -> Self {
+        TaskStoreError::Serialization(e)
+    }
+}
+
+/// State shared by the instances: tasks, their requesters, locks guarding
+/// their builds, TFCS throttles and cache generations.
+#[async_trait]
+pub trait TaskStore: Send + Sync {
+    /// Get the task, extending its lifetime by `TASK_TTL`.
+    async fn get(&self, key: &str) -> Result<Option<Task>, TaskStoreError>;
+
+    async fn insert(&self, task: Task) -> Result<(), TaskStoreError>;
+
+    /// Check the task exists without extending its lifetime.
+    async fn contains(&self, key: &str) -> Result<bool, TaskStoreError>;
+
+    /// All stored tasks, without extending their lifetime.
+    async fn list(&self) -> Result<Vec<Task>, TaskStoreError>;
+
+    /// Drop the task, its archive and requesters.
+    /// Returns `false` if there was no task.
+    async fn remove(&self, key: &str) -> Result<bool, TaskStoreError>;
+
+    /// Remember that `user_id` requested the task.
+    async fn add_requester(&self, key: &str, user_id: i64) -> Result<(), TaskStoreError>;
+
+    /// Users who requested the task.
+    async fn get_requesters(&self, key: &str) -> Result<SmallVec<[i64; 4]>, TaskStoreError>;
+
+    /// Take the build lock of the task for `ttl`, identified by `token`.
+    /// Returns `false` if someone else holds it.
+    async fn try_lock(&self, key: &str, token: &str, ttl: Duration)
+        -> Result<bool, TaskStoreError>;
+
+    /// Release the lock if it is still held with `token`.
+    async fn unlock(&self, key: &str, token: &str) -> Result<(), TaskStoreError>;
+
+    /// Remember that TFCS throttled `user_id` for `ttl`.
+    /// `None` is the shared bucket of anonymous requests.
+    async fn set_throttled(
+        &self,
+        user_id: Option<i64>,
+        ttl: Duration,
+    ) -> Result<(), TaskStoreError>;
+
+    /// Time left until `user_id` may call TFCS again, if it is throttled.
+    async fn get_throttled(&self, user_id: Option<i64>)
+        -> Result<Option<Duration>, TaskStoreError>;
+
+    /// Bump the generation counter `name`, so values cached under older
+    /// generations are refetched on all instances. The counter may be
+    /// dropped after `ttl`, when such values have expired anyway.
+    async fn bump_generation(&self, name: &str, ttl: Duration) -> Result<(), TaskStoreError>;
+
+    /// Current values of the generation counters, 0 for missing ones.
+    async fn get_generations(&self, names: &[String]) -> Result<Vec<u64>, TaskStoreError>;
+
+    /// Apply pending evictions, so their archives are removed.
+    async fn run_pending_tasks(&self) {}
+}
+
+/// Keeps tasks in a process-local cache.
+///
+/// Tasks weigh their archive size in KiB (at least 1), so the cache keeps
+/// stored archives within `ARCHIVES_MAX_SIZE` bytes, and their total size
+/// is published as `archives_size_bytes`.
+pub struct MemoryTaskStore {
+    tasks: Cache<String, Task>,
+    requesters: Cache<String, SmallVec<[i64; 4]>>,
+    /// Lock tokens and expiration times by task key.
+    locks: Mutex<HashMap<String, (String, Instant)>>,
+    /// Moments throttled users may retry.
+    throttled: Cache<Option<i64>, Instant>,
+    /// Generation counters and their expiration times.
+    generations: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+impl MemoryTaskStore {
+    pub fn new(max_size: u64) -> Self {
+        let tasks = Cache::builder()
+            .time_to_idle(TASK_TTL)
+            .eviction_policy(EvictionPolicy::lru())
+            .weigher(|_key, value: &Task| {
+                let kib = value.content_size.unwrap_or(0) / 1024;
+                kib.clamp(1, u32::MAX.into()) as u32
+            })
+            .max_capacity(max_size / 1024)
+            .async_eviction_listener(|_key: Arc<String>, value: Task, reason| {
+                Box::pin(async move {
+                    if let Some(content_size) = value.content_size {
+                        metrics::gauge!("archives_size_bytes").decrement(content_size as f64);
+                    }
+
+                    if reason == RemovalCause::Replaced {
+                        return;
+                    }
+
+                    if let Err(err) = STORAGE.remove(&value.id).await {
+                        log::error!("Can't remove archive {}: {}", value.id, err);
+                    }
+                })
+            })
+            .build();
+
+        MemoryTaskStore {
+            tasks,
+            requesters: Cache::builder()
+                .time_to_idle(TASK_TTL)
+                .max_capacity(2048)
+                .build(),
+            locks: Mutex::new(HashMap::new()),
+            throttled: Cache::builder()
+                .time_to_live(Duration::from_secs(60 * 60))
+                .max_capacity(16384)
+                .build(),
+            generations: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl TaskStore for MemoryTaskStore {
+    async fn get(&self, key: &str) -> Result<Option<Task>, TaskStoreError> {
+        Ok(self.tasks.get(key).await)
+    }
+
+    async fn insert(&self, task: Task) -> Result<(), TaskStoreError> {
+        if let Some(content_size) = task.content_size {
+            metrics::gauge!("archives_size_bytes").increment(content_size as f64);
+        }
+
+        self.tasks.insert(task.id.clone(), task).await;
+        Ok(())
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, TaskStoreError> {
+        Ok(self.tasks.contains_key(key))
+    }
+
+    async fn list(&self) -> Result<Vec<Task>, TaskStoreError> {
+        Ok(self.tasks.iter().map(|(_, task)| task).collect())
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool, TaskStoreError> {
+        self.requesters.invalidate(key).await;
+
+        // The eviction listener removes the archive.
+        Ok(self.tasks.remove(key).await.is_some())
+    }
+
+    async fn add_requester(&self, key: &str, user_id: i64) -> Result<(), TaskStoreError> {
+        self.requesters
+            .entry_by_ref(key)
+            .and_upsert_with(|entry| async move {
+                let mut users = entry.map(|v| v.into_value()).unwrap_or_default();
+                if !users.contains(&user_id) {
+                    users.push(user_id);
+                }
+                users
+            })
+            .await;
+        Ok(())
+    }
+
+    async fn get_requesters(&self, key: &str) -> Result<SmallVec<[i64; 4]>, TaskStoreError> {
+        Ok(self.requesters.get(key).await.unwrap_or_default())
+    }
+
+    async fn try_lock(
+        &self,
+        key: &str,
+        token: &str,
+        ttl: Duration,
+    ) -> Result<bool, TaskStoreError> {
+        let now = Instant::now();
+        let mut locks = self.locks.lock().unwrap();
+
+        if locks
+            .get(key)
+            .is_some_and(|(_, expires_at)| *expires_at > now)
+        {
+            return Ok(false);
+        }
+
+        locks.insert(key.to_string(), (token.to_string(), now + ttl));
+        Ok(true)
+    }
+
+    async fn unlock(&self, key: &str, token: &str) -> Result<(), TaskStoreError> {
+        let mut locks = self.locks.lock().unwrap();
+
+        if locks.get(key).is_some_and(|(v, _)| v == token) {
+            locks.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn set_throttled(
+        &self,
+        user_id: Option<i64>,
+        ttl: Duration,
+    ) -> Result<(), TaskStoreError> {
+        self.throttled.insert(user_id, Instant::now() + ttl).await;
+        Ok(())
+    }
+
+    async fn get_throttled(
+        &self,
+        user_id: Option<i64>,
+    ) -> Result<Option<Duration>, TaskStoreError> {
+        let Some(retry_at) = self.throttled.get(&user_id).await else {
+            return Ok(None);
+        };
+
+        let left = retry_at.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            self.throttled.invalidate(&user_id).await;
+            return Ok(None);
+        }
+
+        Ok(Some(left))
+    }
+
+    async fn bump_generation(&self, name: &str, ttl: Duration) -> Result<(), TaskStoreError> {
+        let now = Instant::now();
+        let mut generations = self.generations.lock().unwrap();
+
+        generations.retain(|_, (_, expires_at)| *expires_at > now);
+
+        let generation = generations.get(name).map_or(0, |(value, _)| *value);
+        generations.insert(name.to_string(), (generation + 1, now + ttl));
+        Ok(())
+    }
+
+    async fn get_generations(&self, names: &[String]) -> Result<Vec<u64>, TaskStoreError> {
+        let now = Instant::now();
+        let generations = self.generations.lock().unwrap();
+
+        Ok(names
+            .iter()
+            .map(|name| match generations.get(name) {
+                Some((value, expires_at)) if *expires_at > now => *value,
+                _ => 0,
+            })
+            .collect())
+    }
+
+    async fn run_pending_tasks(&self) {
+        self.tasks.run_pending_tasks().await;
+    }
+}
+
+/// `Task` skips `library_total` in API responses, so it is stored aside.
+#[derive(Serialize, Deserialize)]
+struct StoredTask {
+    #[serde(flatten)]
+    task: Task,
+    library_total: Option<u32>,
+}
+
+/// Keys requested per `SCAN` iteration.
+const SCAN_COUNT: u64 = 500;
+
+/// Deletes the lock only if it is still held with the given token.
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Keeps tasks in Redis, shared by all instances.
+///
+/// Needs `STORAGE_BACKEND=s3`. Expired tasks don't remove their archives,
+/// so configure the bucket to expire objects after `TASK_TTL`.
+/// `ARCHIVES_MAX_SIZE` is not enforced and `archives_size_bytes` is not published, since expiry isn't observed.
+pub struct RedisTaskStore {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+    prefix: String,
+}
+
+impl RedisTaskStore {
+    pub fn new(url: &str, prefix: String) -> Result<Self, TaskStoreError> {
+        Ok(RedisTaskStore {
+            client: redis::Client::open(url)?,
+            connection: OnceCell::new(),
+            prefix,
+        })
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager, TaskStoreError> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| self.client.get_connection_manager())
+            .await?;
+
+        Ok(connection.clone())
+    }
+
+    fn task_key(&self, key: &str) -> String {
+        format!("{}:task:{}", self.prefix, key)
+    }
+
+    fn lock_key(&self, key: &str) -> String {
+        format!("{}:lock:{}", self.prefix, key)
+    }
+
+    fn requesters_key(&self, key: &str) -> String {
+        format!("{}:requesters:{}", self.prefix, key)
+    }
+
+    fn generation_key(&self, name: &str) -> String {
+        format!("{}:generation:{}", self.prefix, name)
+    }
+
+    fn throttle_key(&self, user_id: Option<i64>) -> String {
+        match user_id {
+            Some(user_id) => format!("{}:throttle:{}", self.prefix, user_id),
+            None => format!("{}:throttle:anonymous", self.prefix),
+        }
+    }
+}
+
+#[async_trait]
+impl TaskStore for RedisTaskStore {
+    async fn get(&self, key: &str) -> Result<Option<Task>, TaskStoreError> {
+        let value: Option<String> = redis::cmd("GETEX")
+            .arg(self.task_key(key))
+            .arg("EX")
+            .arg(TASK_TTL.as_secs())
+            .query_async(&mut self.connection().await?)
+            .await?;
+
+        let Some(value) = value else {
+            return Ok(None);
+        };
+
+        let stored: StoredTask = serde_json::from_str(&value)?;
+
+        Ok(Some(Task {
+            library_total: stored.library_total,
+            ..stored.task
+        }))
+    }
+
+    async fn insert(&self, task: Task) -> Result<(), TaskStoreError> {
+        let key = self.task_key(&task.id);
+        let value = serde_json::to_string(&StoredTask {
+            library_total: task.library_total,
+            task,
+        })?;
+
+        redis::cmd("SET")
+            .arg(key)
+            .arg(value)
+            .arg("EX")
+            .arg(TASK_TTL.as_secs())
+            .query_async::<()>(&mut self.connection().await?)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, TaskStoreError> {
+        let exists: bool = redis::cmd("EXISTS")
+            .arg(self.task_key(key))
+            .query_async(&mut self.connection().await?)
+            .await?;
+
+        Ok(exists)
+    }
+
+    async fn list(&self) -> Result<Vec<Task>, TaskStoreError> {
+        let mut connection = self.connection().await?;
+        let mut cursor: u64 = 0;
+        let mut tasks = vec![];
+
+        loop {
+            let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(self.task_key("*"))
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query_async(&mut connection)
+                .await?;
+
+            if !keys.is_empty() {
+                // Keys may expire between `SCAN` and `MGET`.
+                let values: Vec<Option<String>> = redis::cmd("MGET")
+                    .arg(&keys)
+                    .query_async(&mut connection)
+                    .await?;
+
+                for value in values.into_iter().flatten() {
+                    let stored: StoredTask = serde_json::from_str(&value)?;
+                    tasks.push(Task {
+                        library_total: stored.library_total,
+                        ..stored.task
+                    });
+                }
+            }
+
+            if next_cursor == 0 {
+                return Ok(tasks);
+            }
+            cursor = next_cursor;
+        }
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool, TaskStoreError> {
+        let (removed,): (u64,) = redis::pipe()
+            .cmd("DEL")
+            .arg(self.task_key(key))
+            .cmd("DEL")
+            .arg(self.requesters_key(key))
+            .ignore()
+            .query_async(&mut self.connection().await?)
+            .await?;
+
+        if removed == 0 {
+            return Ok(false);
+        }
+
+        if let Err(err) = STORAGE.remove(key).await {
+            log::error!("Can't remove archive {}: {}", key, err);
+        }
+
+        Ok(true)
+    }
+
+    async fn add_requester(&self, key: &str, user_id: i64) -> Result<(), TaskStoreError> {
+        let key = self.requesters_key(key);
+
+        redis::pipe()
+            .atomic()
+            .cmd("SADD")
+            .arg(&key)
+            .arg(user_id)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(TASK_TTL.as_secs())
+            .ignore()
+            .query_async::<()>(&mut self.connection().await?)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_requesters(&self, key: &str) -> Result<SmallVec<[i64; 4]>, TaskStoreError> {
+        let mut users: Vec<i64> = redis::cmd("SMEMBERS")
+            .arg(self.requesters_key(key))
+            .query_async(&mut self.connection().await?)
+            .await?;
+
+        // Sets are unordered.
+        users.sort();
+        Ok(users.into())
+    }
+
+    async fn try_lock(
+        &self,
+        key: &str,
+        token: &str,
+        ttl: Duration,
+    ) -> Result<bool, TaskStoreError> {
+        let result: Option<String> = redis::cmd("SET")
+            .arg(self.lock_key(key))
+            .arg(token)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut self.connection().await?)
+            .await?;
+
+        Ok(result.is_some())
+    }
+
+    async fn unlock(&self, key: &str, token: &str) -> Result<(), TaskStoreError> {
+        redis::Script::new(UNLOCK_SCRIPT)
+            .key(self.lock_key(key))
+            .arg(token)
+            .invoke_async::<()>(&mut self.connection().await?)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_throttled(
+        &self,
+        user_id: Option<i64>,
+        ttl: Duration,
+    ) -> Result<(), TaskStoreError> {
+        // `PX` doesn't accept zero, and such a throttle is over anyway.
+        if ttl.is_zero() {
+            return Ok(());
+        }
+
+        redis::cmd("SET")
+            .arg(self.throttle_key(user_id))
+            .arg(1)
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async::<()>(&mut self.connection().await?)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_throttled(
+        &self,
+        user_id: Option<i64>,
+    ) -> Result<Option<Duration>, TaskStoreError> {
+        // Negative if the key is missing.
+        let left: i64 = redis::cmd("PTTL")
+            .arg(self.throttle_key(user_id))
+            .query_async(&mut self.connection().await?)
+            .await?;
+
+        Ok((left > 0).then(|| Duration::from_millis(left as u64)))
+    }
+
+    async fn bump_generation(&self, name: &str, ttl: Duration) -> Result<(), TaskStoreError> {
+        let key = self.generation_key(name);
+
+        redis::pipe()
+            .atomic()
+            .cmd("INCR")
+            .arg(&key)
+            .ignore()
+            .cmd("PEXPIRE")
+            .arg(&key)
+            .arg(ttl.as_millis() as u64)
+            .ignore()
+            .query_async::<()>(&mut self.connection().await?)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_generations(&self, names: &[String]) -> Result<Vec<u64>, TaskStoreError> {
+        let keys: Vec<String> = names.iter().map(|name| self.generation_key(name)).collect();
+
+        let values: Vec<Option<u64>> = redis::cmd("MGET")
+            .arg(&keys)
+            .query_async(&mut self.connection().await?)
+            .await?;
+
+        Ok(values.into_iter().map(Option::unwrap_or_default).collect())
+    }
+}
+
+/// Task state selected by `TASK_STORE_BACKEND`.
+pub static TASKS: Lazy<Box<dyn TaskStore>> =
+    Lazy::new(|| match config::CONFIG.task_store_backend {
+        TaskStoreBackend::Memory => {
+            Box::new(MemoryTaskStore::new(config::CONFIG.archives_max_size))
+        }
+        TaskStoreBackend::Redis => Box::new(
+            RedisTaskStore::new(
+                &config::CONFIG.redis_url,
+                config::CONFIG.redis_key_prefix.clone(),
+            )
+            .unwrap_or_else(|err| panic!("Cannot configure Redis: {err}")),
+        ),
+    });
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{MemoryTaskStore, RedisTaskStore, TaskStore};
+    use crate::structures::{Task, TaskStatus};
+
+    async fn roundtrip(store: &dyn TaskStore, key: &str) {
+        let task = Task {
+            result_filename: Some("archive.zip".to_string()),
+            content_size: Some(1024),
+            library_total: Some(10),
+            updated_at: 2,
+            ..Task::fixture(key, TaskStatus::Complete, 1)
+        };
+        store.insert(task).await.unwrap();
+
+        let stored = store.get(key).await.unwrap().unwrap();
+        assert_eq!(stored.result_filename.as_deref(), Some("archive.zip"));
+        assert_eq!(stored.library_total, Some(10));
+        assert!(store.contains(key).await.unwrap());
+        assert!(!store.contains("missing").await.unwrap());
+        assert_eq!(stored.created_at, 1);
+
+        let tasks = store.list().await.unwrap();
+        assert!(tasks.iter().any(|task| task.id == key));
+
+        let ttl = Duration::from_secs(60);
+        assert!(store.try_lock(key, "first", ttl).await.unwrap());
+        assert!(!store.try_lock(key, "second", ttl).await.unwrap());
+
+        // Only the holder releases the lock.
+        store.unlock(key, "second").await.unwrap();
+        assert!(!store.try_lock(key, "second", ttl).await.unwrap());
+
+        store.unlock(key, "first").await.unwrap();
+        assert!(store.try_lock(key, "second", ttl).await.unwrap());
+        store.unlock(key, "second").await.unwrap();
+
+        store.add_requester(key, 2).await.unwrap();
+        store.add_requester(key, 1).await.unwrap();
+        store.add_requester(key, 2).await.unwrap();
+        let mut users = store.get_requesters(key).await.unwrap();
+        users.sort();
+        assert_eq!(users.as_slice(), [1, 2]);
+
+        assert!(store.remove(key).await.unwrap());
+        assert!(!store.remove(key).await.unwrap());
+        assert!(store.get(key).await.unwrap().is_none());
+        assert!(store.get_requesters(key).await.unwrap().is_empty());
+
+        // Throttles are per user.
+        store.set_throttled(Some(1), ttl).await.unwrap();
+        let left = store.get_throttled(Some(1)).await.unwrap().unwrap();
+        assert!(
+            left > Duration::from_secs(59) && left <= ttl,
+            "got {left:?}"
+        );
+        assert!(store.get_throttled(Some(2)).await.unwrap().is_none());
+        assert!(store.get_throttled(None).await.unwrap().is_none());
+
+        store.set_throttled(Some(3), Duration::ZERO).await.unwrap();
+        assert!(store.get_throttled(Some(3)).await.unwrap().is_none());
+
+        let names = vec![format!("{key}:first"), format!("{key}:second")];
+        let before = store.get_generations(&names).await.unwrap();
+        store.bump_generation(&names[0], ttl).await.unwrap();
+        let after = store.get_generations(&names).await.unwrap();
+        assert_eq!(after, vec![before[0] + 1, before[1]]);
+    }
+
+    #[tokio::test]
+    async fn memory_store_roundtrip() {
+        roundtrip(&MemoryTaskStore::new(1024 * 1024), "task").await;
+    }
+
+    #[tokio::test]
+    async fn expired_lock_can_be_taken() {
+        let store = MemoryTaskStore::new(1024 * 1024);
+
+        assert!(store
+            .try_lock("task", "first", Duration::ZERO)
+            .await
+            .unwrap());
+        assert!(store
+            .try_lock("task", "second", Duration::from_secs(60))
+            .await
+            .unwrap());
+    }
+
+    /// Needs a Redis server at `REDIS_URL`.
+    #[tokio::test]
+    #[ignore]
+    async fn redis_store_roundtrip() {
+        let url = std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1/".to_string());
+        let store = RedisTaskStore::new(&url, "test".to_string()).unwrap();
+
+        roundtrip(&store, "task").await;
+    }
+}
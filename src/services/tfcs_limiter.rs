@@ -0,0 +1,266 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use moka::sync::Cache;
+use once_cell::sync::Lazy;
+
+use crate::config;
+
+use super::watchdog;
+
+/// Slowest rate a bucket may adapt down to, requests per second.
+const MIN_RATE: f64 = 0.1;
+/// Rate lost on each 429 (multiplicative decrease).
+const DECREASE_FACTOR: f64 = 0.5;
+/// Share of the configured rate regained on each success (additive increase).
+const INCREASE_SHARE: f64 = 0.05;
+/// Upper bound of the random delay added to every wait.
+const MAX_JITTER_MS: u64 = 250;
+
+/// Token bucket whose refill rate adapts to TFCS 429 responses.
+struct Bucket {
+    max_rate: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl Bucket {
+    fn new(max_rate: f64, now: Instant) -> Self {
+        // A zero rate would never refill the bucket.
+        let max_rate = max_rate.max(MIN_RATE);
+
+        Bucket {
+            max_rate,
+            rate: max_rate,
+            tokens: max_rate.max(1.0),
+            last_refill: now,
+            blocked_until: None,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        // No tokens are earned while blocked by `Retry-After`.
+        if now <= self.last_refill {
+            return;
+        }
+
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.max_rate.max(1.0));
+        self.last_refill = now;
+    }
+
+    /// Time to wait before a token is available; zero if one is available now.
+    fn wait_time(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+
+        if let Some(blocked_until) = self.blocked_until {
+            if blocked_until > now {
+                return blocked_until - now;
+            }
+            self.blocked_until = None;
+        }
+
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        }
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    fn on_success(&mut self) {
+        self.rate = (self.rate + self.max_rate * INCREASE_SHARE).min(self.max_rate);
+    }
+
+    /// Reduce the rate without blocking, for 429s aimed at another bucket.
+    fn slow_down(&mut self) {
+        self.rate = (self.rate * DECREASE_FACTOR).clamp(MIN_RATE, self.max_rate);
+    }
+
+    fn on_rate_limited(&mut self, retry_after_secs: u64, now: Instant) {
+        self.slow_down();
+        self.tokens = 0.0;
+
+        let blocked_until = now + Duration::from_secs(retry_after_secs);
+        if self.blocked_until.is_none_or(|v| v < blocked_until) {
+            self.blocked_until = Some(blocked_until);
+            self.last_refill = blocked_until;
+        }
+    }
+}
+
+static GLOBAL_BUCKET: Lazy<Mutex<Bucket>> =
+    Lazy::new(|| Mutex::new(Bucket::new(config::CONFIG.tfcs_global_rate, Instant::now())));
+
+/// Per `X-User-Id` buckets; `None` is the shared bucket of anonymous requests.
+static USER_BUCKETS: Lazy<Cache<Option<i64>, Arc<Mutex<Bucket>>>> = Lazy::new(|| {
+    Cache::builder()
+        .time_to_idle(Duration::from_secs(60 * 60))
+        .max_capacity(16384)
+        .build()
+});
+
+fn get_user_bucket(user_id: Option<i64>) -> Arc<Mutex<Bucket>> {
+    USER_BUCKETS.get_with(user_id, || {
+        Arc::new(Mutex::new(Bucket::new(
+            config::CONFIG.tfcs_user_rate,
+            Instant::now(),
+        )))
+    })
+}
+
+fn update_metrics(global: &Bucket) {
+    metrics::gauge!("tfcs_limiter_global_rate").set(global.rate);
+    metrics::gauge!("tfcs_limiter_tracked_users").set(USER_BUCKETS.entry_count() as f64);
+}
+
+/// Wait until both the global and the user's bucket allow a TFCS request.
+pub async fn acquire(user_id: Option<i64>) {
+    let user_bucket = get_user_bucket(user_id);
+    let started = Instant::now();
+
+    loop {
+        let wait = {
+            let now = Instant::now();
+            let mut global = GLOBAL_BUCKET.lock().unwrap();
+            let mut user = user_bucket.lock().unwrap();
+
+            let wait = global.wait_time(now).max(user.wait_time(now));
+            if wait.is_zero() {
+                global.take();
+                user.take();
+            }
+            wait
+        };
+
+        if wait.is_zero() {
+            break;
+        }
+
+        // Jitter keeps throttled tasks from waking up at the same moment.
+        let jitter = Duration::from_millis(fastrand::u64(0..=MAX_JITTER_MS));
+        watchdog::beat_current_with_pause(wait + jitter);
+        tokio::time::sleep(wait + jitter).await;
+    }
+
+    metrics::histogram!("tfcs_limiter_wait_seconds").record(started.elapsed().as_secs_f64());
+}
+
+/// Let the buckets speed back up after a successful request.
+pub fn on_success(user_id: Option<i64>) {
+    let mut global = GLOBAL_BUCKET.lock().unwrap();
+    global.on_success();
+    get_user_bucket(user_id).lock().unwrap().on_success();
+
+    update_metrics(&global);
+}
+
+/// Slow the buckets down after a 429 and block the throttled one for
+/// `retry_after_secs`.
+///
+/// TFCS throttles per `X-User-Id`, so a user's 429 only slows the global
+/// bucket down: blocking it would stall every other task. Anonymous
+/// requests share their limit, so their 429 blocks the global bucket too.
+pub fn on_rate_limited(user_id: Option<i64>, retry_after_secs: u64) {
+    let now = Instant::now();
+
+    let mut global = GLOBAL_BUCKET.lock().unwrap();
+    if user_id.is_none() {
+        global.on_rate_limited(retry_after_secs, now);
+    } else {
+        global.slow_down();
+    }
+    get_user_bucket(user_id)
+        .lock()
+        .unwrap()
+        .on_rate_limited(retry_after_secs, now);
+
+    metrics::counter!("tfcs_limiter_throttled_total").increment(1);
+    update_metrics(&global);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Bucket, MIN_RATE};
+
+    #[test]
+    fn bucket_allows_burst_then_waits() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(2.0, now);
+
+        for _ in 0..2 {
+            assert!(bucket.wait_time(now).is_zero());
+            bucket.take();
+        }
+
+        let wait = bucket.wait_time(now);
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(500));
+
+        assert!(bucket.wait_time(now + wait).is_zero());
+    }
+
+    #[test]
+    fn rate_limit_blocks_and_slows_down() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(4.0, now);
+
+        bucket.on_rate_limited(10, now);
+
+        assert_eq!(bucket.rate, 2.0);
+        assert_eq!(bucket.wait_time(now), Duration::from_secs(10));
+        assert!(bucket.wait_time(now + Duration::from_secs(10)) > Duration::ZERO);
+    }
+
+    #[test]
+    fn zero_rate_is_clamped() {
+        let now = Instant::now();
+
+        for rate in [0.0, -1.0, f64::NAN] {
+            let mut bucket = Bucket::new(rate, now);
+            assert_eq!(bucket.rate, MIN_RATE);
+
+            bucket.take();
+            bucket.on_rate_limited(0, now);
+            assert_eq!(bucket.rate, MIN_RATE);
+            assert!(bucket.wait_time(now) <= Duration::from_secs_f64(1.0 / MIN_RATE));
+        }
+    }
+
+    #[test]
+    fn slow_down_does_not_block() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(4.0, now);
+
+        bucket.slow_down();
+
+        assert_eq!(bucket.rate, 2.0);
+        assert!(bucket.wait_time(now).is_zero());
+    }
+
+    #[test]
+    fn rate_recovers_but_not_above_max() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(1.0, now);
+
+        for _ in 0..20 {
+            bucket.on_rate_limited(0, now);
+        }
+        assert_eq!(bucket.rate, MIN_RATE);
+
+        for _ in 0..100 {
+            bucket.on_success();
+        }
+        assert_eq!(bucket.rate, 1.0);
+    }
+}
@@ -4,35 +4,134 @@ use smartstring::alias::String as SmartString;
 use tempfile::SpooledTempFile;
 use translit::{gost779b_ru, CharsMapping, Transliterator};
 
-use std::io::{Seek, SeekFrom, Write};
+use std::{
+    io::{Seek, SeekFrom, Write},
+    time::{Duration, Instant},
+};
 
-use crate::structures::{CreateTask, ObjectType};
+use crate::{
+    config,
+    errors::ServiceError,
+    structures::{CreateTask, ObjectType},
+};
+
+use once_cell::sync::Lazy;
+
+use super::{
+    cache_client::CacheClientError,
+    crypto::{TaskSecret, TASK_SECRET},
+    watchdog,
+};
 
 use super::library_client::{get_author, get_sequence};
 
+/// Current Unix time in seconds.
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0)
+}
+
 pub fn get_key(input_data: CreateTask) -> String {
+    build_key(input_data, &TASK_SECRET)
+}
+
+/// `secret` is only loaded for tasks with a password.
+fn build_key(input_data: CreateTask, secret: &Lazy<TaskSecret>) -> String {
     let mut data = input_data.clone();
     data.allowed_langs.sort();
-
-    let data_string = serde_json::to_string(&data).unwrap();
+    // Requesters are tracked by the task store, so the same archive
+    // is shared by every user asking for the same content.
+    data.user_id = None;
+
+    // `password` is skipped by serde, so mix in its digest separately:
+    // archives encrypted with different passwords must not share a key, and
+    // the key is public, so the password can't be recoverable from it.
+    let mut data_string = serde_json::to_string(&data).unwrap();
+    if let Some(password) = &data.password {
+        data_string.push('\n');
+        data_string.push_str(&secret.password_digest(password));
+    }
 
     format!("{:x}", md5::compute(data_string))
 }
 
+/// Detects downloads whose throughput drops below `min_bytes_per_sec`,
+/// measured over consecutive windows of `window` length.
+/// A zero `window` disables the detection.
+struct StallDetector {
+    window: Duration,
+    min_bytes_per_sec: u64,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl StallDetector {
+    fn new(window: Duration, min_bytes_per_sec: u64, now: Instant) -> Self {
+        StallDetector {
+            window,
+            min_bytes_per_sec,
+            window_start: now,
+            window_bytes: 0,
+        }
+    }
+
+    /// Time left until the current window closes.
+    fn time_left(&self, now: Instant) -> Duration {
+        if self.window.is_zero() {
+            return Duration::MAX;
+        }
+
+        self.window
+            .saturating_sub(now.saturating_duration_since(self.window_start))
+    }
+
+    /// Account received bytes; returns `false` if the closed window was too slow.
+    fn record(&mut self, bytes: u64, now: Instant) -> bool {
+        self.window_bytes += bytes;
+
+        if self.window.is_zero() || !self.time_left(now).is_zero() {
+            return true;
+        }
+
+        let is_ok = self.window_bytes >= self.min_bytes_per_sec * self.window.as_secs();
+        self.window_start = now;
+        self.window_bytes = 0;
+        is_ok
+    }
+}
+
 pub async fn response_to_tempfile(
     res: &mut Response,
-) -> Result<(SpooledTempFile, usize), Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<(SpooledTempFile, usize), ServiceError> {
     let mut tmp_file = tempfile::spooled_tempfile(5 * 1024 * 1024);
 
     let mut data_size: usize = 0;
 
+    let mut stall_detector = StallDetector::new(
+        Duration::from_secs(config::CONFIG.download_stall_window_secs),
+        config::CONFIG.download_min_throughput,
+        Instant::now(),
+    );
+
     {
         loop {
-            let chunk = res.chunk().await;
+            let time_left = stall_detector.time_left(Instant::now());
+            let chunk = match tokio::time::timeout(time_left, res.chunk()).await {
+                Ok(v) => v,
+                // No data at all until the window closed.
+                Err(_) => {
+                    if stall_detector.record(0, Instant::now()) {
+                        continue;
+                    }
+                    return Err(ServiceError::Timeout("download stalled".to_string()));
+                }
+            };
 
             let result = match chunk {
                 Ok(v) => v,
-                Err(err) => return Err(Box::new(err)),
+                Err(err) => return Err(CacheClientError::Reqwest(err).into()),
             };
 
             let data = match result {
@@ -41,6 +140,12 @@ pub async fn response_to_tempfile(
             };
 
             data_size += data.len();
+            // A single book may take longer than `TASK_STALL_TIMEOUT_SECS`.
+            watchdog::beat_current();
+
+            if !stall_detector.record(data.len() as u64, Instant::now()) {
+                return Err(ServiceError::Timeout("download stalled".to_string()));
+            }
 
             tmp_file.write_all(data.chunk())?;
         }
@@ -61,7 +166,7 @@ pub async fn get_filename(
     object_id: u32,
     file_format: SmartString,
     normalized: bool,
-) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<String, ServiceError> {
     let result_filename = match object_type {
         ObjectType::Sequence => match get_sequence(object_id).await {
             Ok(v) => v.name,
@@ -115,6 +220,43 @@ pub async fn get_filename(
 /// 8. Collapse trailing separators (`_`, `-`, `.`, space) in `<left>`.
 /// 9. Glue as `{left}.{file_format}.zip`.
 pub fn normalize_filename(input: &str, normalized: bool, file_format: &str) -> String {
+    // 1.-6.
+    let s = sanitize_filename_part(input, normalized);
+
+    // 7. Trim <left> to LEFT_MAX_BYTES UTF-8 bytes.
+    let right_part = format!(".{file_format}.zip");
+    let left_max = LEFT_MAX_BYTES.min(s.len());
+    let slice_end = s.floor_char_boundary(left_max);
+    let mut left_part = &s[..slice_end];
+
+    // 8. Collapse trailing separators in <left>.
+    while let Some(last) = left_part.chars().last() {
+        if matches!(last, '_' | '-' | '.' | ' ') {
+            left_part = &left_part[..left_part.len() - last.len_utf8()];
+        } else {
+            break;
+        }
+    }
+
+    // 9. Glue.
+    format!("{left_part}{right_part}")
+}
+
+/// Filename of a book TFCS didn't name: `{book_id}.{file_format}`, with every
+/// dot-separated part of the format cleaned by the `normalize_filename` rules.
+pub fn get_fallback_filename(book_id: u64, file_format: &str, normalized: bool) -> String {
+    let extension = file_format
+        .split('.')
+        .map(|part| sanitize_filename_part(part, normalized))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<String>>()
+        .join(".");
+
+    format!("{book_id}.{extension}")
+}
+
+/// Steps 1-6 of `normalize_filename`: character-level cleanup.
+fn sanitize_filename_part(input: &str, normalized: bool) -> String {
     // 1. Pre-cleanup (always, before transliteration so that GOST doesn't
     //    turn `№` into `#`).
     let mut s = input.replace('№', "N").replace(['«', '»'], "");
@@ -158,28 +300,97 @@ pub fn normalize_filename(input: &str, normalized: bool, file_format: &str) -> S
         s = s.replace(ch, "");
     }
 
-    // 7. Trim <left> to LEFT_MAX_BYTES UTF-8 bytes.
-    let right_part = format!(".{file_format}.zip");
-    let left_max = LEFT_MAX_BYTES.min(s.len());
-    let slice_end = s.floor_char_boundary(left_max);
-    let mut left_part = &s[..slice_end];
+    s
+}
 
-    // 8. Collapse trailing separators in <left>.
-    while let Some(last) = left_part.chars().last() {
-        if matches!(last, '_' | '-' | '.' | ' ') {
-            left_part = &left_part[..left_part.len() - last.len_utf8()];
-        } else {
-            break;
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use once_cell::sync::Lazy;
+
+    use super::{build_key, get_fallback_filename, get_key, normalize_filename, StallDetector};
+    use crate::{services::crypto::TaskSecret, structures::CreateTask};
+
+    static TEST_SECRET: Lazy<TaskSecret> = Lazy::new(|| TaskSecret::new(b"test"));
+
+    fn create_task_data(password: Option<&str>) -> CreateTask {
+        CreateTask {
+            password: password.map(|v| v.to_string()),
+            ..CreateTask::fixture()
         }
     }
 
-    // 9. Glue.
-    format!("{left_part}{right_part}")
-}
+    #[test]
+    fn password_is_not_serialized() {
+        let data_string = serde_json::to_string(&create_task_data(Some("secret"))).unwrap();
+        assert!(!data_string.contains("secret"), "got: {data_string}");
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::normalize_filename;
+    #[test]
+    fn key_depends_on_password() {
+        let key = |password| build_key(create_task_data(password), &TEST_SECRET);
+        let plain = key(None);
+        let first = key(Some("first"));
+        let second = key(Some("second"));
+
+        assert_eq!(plain, get_key(create_task_data(None)));
+        assert_ne!(plain, first);
+        assert_ne!(first, second);
+        assert_eq!(first, key(Some("first")));
+
+        // The plaintext password isn't part of the hashed input.
+        let mut data_string = serde_json::to_string(&create_task_data(None)).unwrap();
+        data_string.push_str("\nfirst");
+        assert_ne!(first, format!("{:x}", md5::compute(data_string)));
+    }
+
+    #[test]
+    fn key_ignores_user_id() {
+        let mut data = create_task_data(None);
+        let anonymous = get_key(data.clone());
+
+        data.user_id = Some(42);
+        assert_eq!(anonymous, get_key(data.clone()));
+
+        data.user_id = Some(7);
+        assert_eq!(anonymous, get_key(data));
+    }
+
+    #[test]
+    fn key_ignores_force_rebuild() {
+        let mut data = create_task_data(None);
+        let key = get_key(data.clone());
+
+        data.force_rebuild = true;
+        assert_eq!(key, get_key(data));
+    }
+
+    #[test]
+    fn stall_detector_checks_throughput_per_window() {
+        let now = Instant::now();
+        let mut detector = StallDetector::new(Duration::from_secs(10), 100, now);
+
+        // Slow start is fine while the window is open.
+        assert!(detector.record(10, now + Duration::from_secs(5)));
+        assert_eq!(
+            detector.time_left(now + Duration::from_secs(5)),
+            Duration::from_secs(5)
+        );
+
+        // 1000 bytes in 10 seconds is exactly the minimum.
+        assert!(detector.record(990, now + Duration::from_secs(10)));
+
+        // The next window only gets 999 bytes.
+        assert!(!detector.record(999, now + Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn fallback_filename_keeps_format_parts() {
+        assert_eq!(get_fallback_filename(42, "fb2", true), "42.fb2");
+        assert_eq!(get_fallback_filename(42, "fb2.zip", true), "42.fb2.zip");
+        assert_eq!(get_fallback_filename(42, "e/p:ub", false), "42.e_pub");
+    }
 
     #[test]
     fn normalized_true_transliterates() {
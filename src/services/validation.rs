@@ -0,0 +1,234 @@
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader,
+};
+use tempfile::SpooledTempFile;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const PDF_MAGIC: &[u8] = b"%PDF-";
+const DJVU_MAGIC: &[u8] = b"AT&TFORM";
+/// MOBI files are PalmDOC databases with the type at offset 60.
+const MOBI_MAGIC_OFFSET: usize = 60;
+const MOBI_MAGIC: &[u8] = b"BOOKMOBI";
+const FB2_ROOT: &[u8] = b"FictionBook";
+
+/// Check a downloaded book before adding it to an archive.
+///
+/// `expected_size` is the `Content-Length` announced by TFCS, if any.
+/// Returns the reason of the rejection on failure. `file` is rewound
+/// before returning.
+pub fn validate_book(
+    file: &mut SpooledTempFile,
+    file_type: &str,
+    expected_size: Option<u64>,
+    received_size: u64,
+) -> Result<(), String> {
+    if let Some(expected_size) = expected_size {
+        if expected_size != received_size {
+            return Err(format!(
+                "expected {expected_size} bytes, received {received_size}"
+            ));
+        }
+    }
+
+    if received_size == 0 {
+        return Err("file is empty".to_string());
+    }
+
+    let result = validate_format(file, file_type);
+    file.seek(SeekFrom::Start(0))
+        .map_err(|err| format!("can't rewind file: {err}"))?;
+    result
+}
+
+fn validate_format(file: &mut SpooledTempFile, file_type: &str) -> Result<(), String> {
+    match file_type {
+        "fb2" => validate_xml(file),
+        "epub" | "fb2zip" | "fb2.zip" | "zip" => validate_zip(file),
+        "pdf" => check_magic(file, 0, PDF_MAGIC),
+        "djvu" => check_magic(file, 0, DJVU_MAGIC),
+        "mobi" => check_magic(file, MOBI_MAGIC_OFFSET, MOBI_MAGIC),
+        // Nothing reliable to check for other formats.
+        _ => Ok(()),
+    }
+}
+
+fn check_magic(file: &mut SpooledTempFile, offset: usize, magic: &[u8]) -> Result<(), String> {
+    let mut header = vec![0; offset + magic.len()];
+
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_exact(&mut header))
+        .map_err(|_| "file is too short".to_string())?;
+
+    if &header[offset..] != magic {
+        return Err("unexpected file signature".to_string());
+    }
+
+    Ok(())
+}
+
+fn validate_zip(file: &mut SpooledTempFile) -> Result<(), String> {
+    check_magic(file, 0, ZIP_MAGIC)?;
+
+    // Parses the central directory.
+    zip::ZipArchive::new(&mut *file).map_err(|err| format!("invalid zip: {err}"))?;
+
+    Ok(())
+}
+
+fn check_fb2_root(root: &BytesStart) -> Result<(), String> {
+    if root.local_name().as_ref() != FB2_ROOT {
+        return Err(format!(
+            "unexpected root element: {}",
+            String::from_utf8_lossy(root.name().as_ref())
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that the file is well-formed XML with a `FictionBook` root.
+fn validate_xml(file: &mut SpooledTempFile) -> Result<(), String> {
+    file.seek(SeekFrom::Start(0))
+        .map_err(|err| format!("can't read file: {err}"))?;
+
+    let mut reader = Reader::from_reader(BufReader::new(&mut *file));
+    let mut buf = Vec::new();
+    let mut depth: usize = 0;
+    let mut has_root = false;
+
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(element)) => {
+                if !has_root {
+                    check_fb2_root(&element)?;
+                }
+                depth += 1;
+                has_root = true;
+            }
+            Ok(Event::Empty(element)) => {
+                if !has_root {
+                    check_fb2_root(&element)?;
+                }
+                has_root = true;
+            }
+            Ok(Event::End(_)) => depth = depth.saturating_sub(1),
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(err) => return Err(format!("invalid xml: {err}")),
+        }
+
+        buf.clear();
+    }
+
+    if !has_root {
+        return Err("invalid xml: no root element".to_string());
+    }
+
+    if depth != 0 {
+        return Err("invalid xml: unclosed elements".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+
+    use tempfile::SpooledTempFile;
+
+    use super::validate_book;
+
+    fn tmp_file(content: &[u8]) -> (SpooledTempFile, u64) {
+        let mut file = tempfile::spooled_tempfile(1024);
+        file.write_all(content).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        (file, content.len() as u64)
+    }
+
+    fn zip_file() -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        writer
+            .start_file::<_, ()>("book.fb2", zip::write::FileOptions::default())
+            .unwrap();
+        writer.write_all(b"<FictionBook/>").unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn length_mismatch_is_rejected() {
+        let (mut file, size) = tmp_file(b"<FictionBook></FictionBook>");
+
+        assert!(validate_book(&mut file, "fb2", Some(size + 1), size).is_err());
+        assert!(validate_book(&mut file, "fb2", Some(size), size).is_ok());
+        assert!(validate_book(&mut file, "fb2", None, size).is_ok());
+    }
+
+    #[test]
+    fn fb2_must_be_well_formed_xml() {
+        let (mut file, size) =
+            tmp_file(b"<?xml version=\"1.0\"?><FictionBook><body></body></FictionBook>");
+        assert!(validate_book(&mut file, "fb2", None, size).is_ok());
+
+        let (mut file, size) = tmp_file(b"<FictionBook><body></FictionBook>");
+        assert!(validate_book(&mut file, "fb2", None, size).is_err());
+
+        let (mut file, size) = tmp_file(b"<FictionBook><body>");
+        assert!(validate_book(&mut file, "fb2", None, size).is_err());
+
+        let (mut file, size) = tmp_file(b"<html>Not found</html");
+        assert!(validate_book(&mut file, "fb2", None, size).is_err());
+    }
+
+    #[test]
+    fn fb2_root_must_be_fiction_book() {
+        let (mut file, size) =
+            tmp_file(b"<!DOCTYPE html><html><head></head><body><p>Bad gateway</p></body></html>");
+        assert!(validate_book(&mut file, "fb2", None, size).is_err());
+
+        let (mut file, size) = tmp_file(b"{\"detail\": \"<error/>\"}");
+        assert!(validate_book(&mut file, "fb2", None, size).is_err());
+
+        let (mut file, size) =
+            tmp_file(b"<fb:FictionBook xmlns:fb=\"http://www.gribuser.ru/xml/fictionbook/2.0\"/>");
+        assert!(validate_book(&mut file, "fb2", None, size).is_ok());
+    }
+
+    #[test]
+    fn zip_based_formats_need_central_directory() {
+        let content = zip_file();
+
+        let (mut file, size) = tmp_file(&content);
+        assert!(validate_book(&mut file, "epub", None, size).is_ok());
+
+        // Truncated archive keeps the signature but loses the directory.
+        let (mut file, size) = tmp_file(&content[..content.len() / 2]);
+        assert!(validate_book(&mut file, "fb2zip", None, size).is_err());
+
+        let (mut file, size) = tmp_file(b"<FictionBook/>");
+        assert!(validate_book(&mut file, "epub", None, size).is_err());
+    }
+
+    #[test]
+    fn magic_bytes_are_checked() {
+        let (mut file, size) = tmp_file(b"%PDF-1.7 ...");
+        assert!(validate_book(&mut file, "pdf", None, size).is_ok());
+
+        let (mut file, size) = tmp_file(b"<html></html>");
+        assert!(validate_book(&mut file, "pdf", None, size).is_err());
+
+        let mut mobi = vec![0; 60];
+        mobi.extend_from_slice(b"BOOKMOBI");
+        let (mut file, size) = tmp_file(&mobi);
+        assert!(validate_book(&mut file, "mobi", None, size).is_ok());
+    }
+
+    #[test]
+    fn empty_file_is_rejected() {
+        let (mut file, size) = tmp_file(b"");
+        assert!(validate_book(&mut file, "txt", None, size).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use tokio::task::AbortHandle;
+use tracing::log;
+
+use crate::{config, structures::ErrorCode};
+
+use super::{
+    storage::get_partial_path,
+    task_creator::{release_build, set_task_error},
+    task_store::TASKS,
+};
+
+/// Upper bound of the time between two supervisor sweeps.
+const MAX_CHECK_INTERVAL_SECS: u64 = 30;
+
+struct RunningTask {
+    abort_handle: AbortHandle,
+    /// The task is considered stalled if no heartbeat arrives until then.
+    deadline: Instant,
+}
+
+static RUNNING_TASKS: Lazy<Mutex<HashMap<String, RunningTask>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+tokio::task_local! {
+    /// Key of the task built by the current tokio task, see `scope`.
+    static CURRENT_TASK: String;
+}
+
+fn stall_timeout() -> Duration {
+    Duration::from_secs(config::CONFIG.task_stall_timeout_secs)
+}
+
+/// Start supervising the spawned build of the task.
+pub fn register(key: String, abort_handle: AbortHandle) {
+    RUNNING_TASKS.lock().unwrap().insert(
+        key,
+        RunningTask {
+            abort_handle,
+            deadline: Instant::now() + stall_timeout(),
+        },
+    );
+}
+
+/// Record progress of the task.
+pub fn beat(key: &str) {
+    extend(key, Duration::ZERO);
+}
+
+/// Record progress of the task that is going to wait for `pause` on purpose.
+pub fn beat_with_pause(key: &str, pause: Duration) {
+    extend(key, pause);
+}
+
+/// Run the build of the task, so that code deep inside it, e.g. the TFCS
+/// client, can send heartbeats with `beat_current`.
+pub async fn scope<F: Future>(key: String, build: F) -> F::Output {
+    CURRENT_TASK.scope(key, build).await
+}
+
+/// Record progress of the build running in the current tokio task, if any.
+pub fn beat_current() {
+    beat_current_with_pause(Duration::ZERO);
+}
+
+/// Same as `beat_with_pause` for the build running in the current tokio
+/// task, if any.
+pub fn beat_current_with_pause(pause: Duration) {
+    if let Some(key) = current_task() {
+        extend(&key, pause);
+    }
+}
+
+/// Key of the build running in the current tokio task, if any.
+fn current_task() -> Option<String> {
+    CURRENT_TASK.try_with(|key| key.clone()).ok()
+}
+
+/// Stop supervising the build of the task, called when it ends.
+pub fn unregister(key: &str) {
+    RUNNING_TASKS.lock().unwrap().remove(key);
+}
+
+/// Abort the build of the task and stop supervising it.
+pub fn abort(key: &str) {
+    if let Some(task) = RUNNING_TASKS.lock().unwrap().remove(key) {
+        task.abort_handle.abort();
+    }
+}
+
+fn extend(key: &str, pause: Duration) {
+    if let Some(task) = RUNNING_TASKS.lock().unwrap().get_mut(key) {
+        task.deadline = Instant::now() + pause + stall_timeout();
+    }
+}
+
+/// Fail tasks whose build stopped sending heartbeats or died without
+/// reporting a result, so the next request rebuilds them.
+async fn check_tasks() {
+    let now = Instant::now();
+    let mut stalled: Vec<(String, &'static str)> = vec![];
+
+    RUNNING_TASKS.lock().unwrap().retain(|key, task| {
+        if task.abort_handle.is_finished() {
+            stalled.push((key.clone(), "Task stopped unexpectedly!"));
+            return false;
+        }
+
+        if task.deadline <= now {
+            task.abort_handle.abort();
+            stalled.push((key.clone(), "Task stalled!"));
+            return false;
+        }
+
+        true
+    });
+
+    for (key, error_message) in stalled {
+        // Finished builds normally leave a final status behind.
+        let is_stalled = match TASKS.get(&key).await {
+            Ok(Some(task)) => task.status.is_active(),
+            Ok(None) => false,
+            Err(err) => {
+                log::error!("Watchdog: can't get task {}: {}", key, err);
+                false
+            }
+        };
+
+        if is_stalled {
+            log::error!("Watchdog: {} {}", key, error_message);
+            metrics::counter!("watchdog_failed_tasks_total").increment(1);
+            set_task_error(key.clone(), error_message.to_string(), ErrorCode::Stalled).await;
+        }
+
+        let _ = tokio::fs::remove_file(get_partial_path(&key)).await;
+
+        // Aborted builds don't get to release their lock.
+        release_build(&key).await;
+    }
+}
+
+/// Periodically check the running tasks, see `check_tasks`.
+pub async fn run() {
+    let interval = (stall_timeout() / 4).clamp(
+        Duration::from_secs(1),
+        Duration::from_secs(MAX_CHECK_INTERVAL_SECS),
+    );
+
+    loop {
+        tokio::time::sleep(interval).await;
+        check_tasks().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current_task, scope};
+
+    #[tokio::test]
+    async fn current_task_is_set_inside_build_scope() {
+        assert_eq!(current_task(), None);
+
+        let key = scope("scoped".to_string(), async { current_task() }).await;
+        assert_eq!(key.as_deref(), Some("scoped"));
+
+        // Other tokio tasks don't inherit the scope.
+        let key = scope("scoped".to_string(), async {
+            tokio::spawn(async { current_task() }).await.unwrap()
+        })
+        .await;
+        assert_eq!(key, None);
+    }
+}
@@ -1,17 +1,103 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use smartstring::alias::String as SmartString;
 
-#[derive(Serialize, Clone, PartialEq)]
+use crate::services::utils::unix_time;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum TaskStatus {
     InProgress,
     Archiving,
     Complete,
     Failed,
+    /// TFCS throttled the build; retry after `retry_after_secs`.
+    RateLimited,
+    /// TFCS throttled the build; it resumes by itself after `retry_after_secs`.
+    Paused,
+}
+
+impl TaskStatus {
+    /// The build is still running.
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self,
+            TaskStatus::InProgress | TaskStatus::Archiving | TaskStatus::Paused
+        )
+    }
+}
+
+/// Stable machine-readable reason of a failed task.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    LibraryError,
+    LibraryUnavailable,
+    TfcsError,
+    RateLimited,
+    IoError,
+    ZipError,
+    ValidationError,
+    NoBooks,
+    Timeout,
+    Stalled,
+    InsufficientStorage,
+    /// The instance shut down before the build finished.
+    Interrupted,
+    /// The worker couldn't decrypt the archive password.
+    PasswordUnavailable,
+}
+
+impl ErrorCode {
+    /// Same as the serialized value, for metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::LibraryError => "library_error",
+            ErrorCode::LibraryUnavailable => "library_unavailable",
+            ErrorCode::TfcsError => "tfcs_error",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::IoError => "io_error",
+            ErrorCode::ZipError => "zip_error",
+            ErrorCode::ValidationError => "validation_error",
+            ErrorCode::NoBooks => "no_books",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::Stalled => "stalled",
+            ErrorCode::InsufficientStorage => "insufficient_storage",
+            ErrorCode::Interrupted => "interrupted",
+            ErrorCode::PasswordUnavailable => "password_unavailable",
+        }
+    }
+}
+
+/// Why a book was left out of the archive.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    DownloadFailed,
+    InvalidFile,
+    DuplicateFilename,
+}
+
+impl SkipReason {
+    /// Same as the serialized value, for metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::DownloadFailed => "download_failed",
+            SkipReason::InvalidFile => "invalid_file",
+            SkipReason::DuplicateFilename => "duplicate_filename",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SkippedBook {
+    pub book_id: u64,
+    pub reason: SkipReason,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum ObjectType {
     Sequence,
@@ -19,6 +105,17 @@ pub enum ObjectType {
     Translator,
 }
 
+impl ObjectType {
+    /// Same as the serialized value, for metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ObjectType::Sequence => "sequence",
+            ObjectType::Author => "author",
+            ObjectType::Translator => "translator",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct CreateTask {
     pub object_id: u32,
@@ -35,19 +132,178 @@ pub struct CreateTask {
     /// `false` keeps the original Cyrillic in the filename.
     #[serde(default = "default_true")]
     pub normalized: bool,
+
+    /// Optional password for AES-256 encryption of the archive entries.
+    /// Never serialized: it stays out of the task key input and any responses.
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+
+    /// Rebuild the archive even if a completed one is available.
+    #[serde(default, skip_serializing)]
+    pub force_rebuild: bool,
+}
+
+#[cfg(test)]
+impl CreateTask {
+    /// Anonymous request for the Russian fb2 books of author 1.
+    pub fn fixture() -> Self {
+        CreateTask {
+            object_id: 1,
+            object_type: ObjectType::Author,
+            file_format: "fb2".into(),
+            allowed_langs: smallvec::smallvec!["ru".into()],
+            user_id: None,
+            normalized: true,
+            password: None,
+            force_rebuild: false,
+        }
+    }
 }
 
 fn default_true() -> bool {
     true
 }
 
-#[derive(Serialize, Clone)]
+/// What the task builds, kept for the admin API.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TaskRequest {
+    pub object_id: u32,
+    pub object_type: ObjectType,
+    pub file_format: SmartString,
+    pub allowed_langs: SmallVec<[SmartString; 3]>,
+    pub normalized: bool,
+    /// Passwords aren't kept, so encrypted archives can't be rebuilt by admins.
+    pub encrypted: bool,
+}
+
+impl From<&CreateTask> for TaskRequest {
+    fn from(data: &CreateTask) -> Self {
+        TaskRequest {
+            object_id: data.object_id,
+            object_type: data.object_type.clone(),
+            file_format: data.file_format.clone(),
+            allowed_langs: data.allowed_langs.clone(),
+            normalized: data.normalized,
+            encrypted: data.password.is_some(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Task {
     pub id: String,
     pub status: TaskStatus,
     pub status_description: String,
     pub error_message: Option<String>,
+    pub error_code: Option<ErrorCode>,
+
+    /// Set for `RateLimited` and `Paused` tasks: seconds until TFCS accepts
+    /// the user again and the user whose requests were throttled, see
+    /// `Task::for_user`.
+    pub retry_after_secs: Option<u64>,
+    pub rate_limited_user_id: Option<i64>,
 
     pub result_filename: Option<String>,
     pub content_size: Option<u64>,
+    pub skipped_books: Vec<SkippedBook>,
+
+    /// Number of books in the library listing when the archive was built.
+    /// Used to detect books added since then.
+    #[serde(skip)]
+    pub library_total: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<TaskRequest>,
+    /// Unix timestamps of the task creation and its last status change.
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub updated_at: u64,
+}
+
+impl Task {
+    /// Task with the given status created now, other fields are empty.
+    pub fn new(id: String, status: TaskStatus, status_description: String) -> Self {
+        let now = unix_time();
+
+        Task {
+            id,
+            status,
+            status_description,
+            error_message: None,
+            error_code: None,
+            retry_after_secs: None,
+            rate_limited_user_id: None,
+            result_filename: None,
+            content_size: None,
+            skipped_books: vec![],
+            library_total: None,
+            request: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// The task as shown to `user_id`: the task is shared by every user
+    /// requesting the same archive, so only the throttled user sees its id.
+    pub fn for_user(mut self, user_id: Option<i64>) -> Self {
+        if self.rate_limited_user_id != user_id {
+            self.rate_limited_user_id = None;
+        }
+
+        self
+    }
+}
+
+#[cfg(test)]
+impl Task {
+    /// Task created at `created_at` and not changed since.
+    pub fn fixture(id: &str, status: TaskStatus, created_at: u64) -> Self {
+        Task {
+            created_at,
+            updated_at: created_at,
+            ..Task::new(id.to_string(), status, "".to_string())
+        }
+    }
+}
+
+/// Filters of the admin task list.
+#[derive(Deserialize, Default)]
+pub struct TaskListQuery {
+    pub status: Option<TaskStatus>,
+    pub object_type: Option<ObjectType>,
+    pub user_id: Option<i64>,
+    /// Only tasks created at least this many seconds ago.
+    pub min_age_secs: Option<u64>,
+    /// Only tasks created at most this many seconds ago.
+    pub max_age_secs: Option<u64>,
+}
+
+/// Task as seen by the admin API.
+#[derive(Serialize)]
+pub struct TaskDetails {
+    #[serde(flatten)]
+    pub task: Task,
+    pub library_total: Option<u32>,
+    pub requested_by: SmallVec<[i64; 4]>,
+    pub age_secs: u64,
+    /// Time from the creation to the last status change.
+    pub duration_secs: u64,
+}
+
+#[derive(Serialize)]
+pub struct ReadinessCheck {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The check doesn't apply to this instance.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
+}
+
+/// Response of `/ready`.
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, ReadinessCheck>,
 }
@@ -1,44 +1,40 @@
-use std::time::Duration;
-
 use axum::{
     body::Body,
-    extract::Path,
+    extract::{Path, Query},
     http::{self, Request, StatusCode},
     middleware::{self, Next},
-    response::{IntoResponse, Response},
-    routing::{get, post},
+    response::{IntoResponse, Redirect, Response},
+    routing::{delete, get, post},
     Json, Router,
 };
 use axum_prometheus::PrometheusMetricLayer;
-use moka::{future::Cache, notification::RemovalCause};
-use once_cell::sync::Lazy;
-use tokio::fs::File;
-use tokio_util::io::ReaderStream;
 use tower_http::trace::{self, TraceLayer};
 
-use tracing::Level;
+use tracing::{error, Level};
 
 use crate::{
-    config::CONFIG,
-    services::{task_creator::create_task, utils::get_key},
-    structures::{CreateTask, Task, TaskStatus},
+    config::{DownloadMode, CONFIG},
+    services::{
+        admin::{self, AdminError},
+        library_client::{invalidate_all_caches, invalidate_cache},
+        rate_limits::get_retry_after,
+        readiness::check_readiness,
+        shutdown,
+        storage::{has_free_space, STORAGE},
+        task_creator::{create_task, is_archive_stale, is_task_abandoned, track_requester},
+        task_store::TASKS,
+        utils::{get_key, unix_time},
+    },
+    structures::{CreateTask, ObjectType, Task, TaskListQuery, TaskStatus},
 };
 
-pub static TASK_RESULTS: Lazy<Cache<String, Task>> = Lazy::new(|| {
-    Cache::builder()
-        .time_to_idle(Duration::from_secs(3 * 60 * 60))
-        .max_capacity(2048)
-        .async_eviction_listener(|_key, value: Task, reason| {
-            Box::pin(async move {
-                if reason == RemovalCause::Replaced {
-                    return;
-                }
-
-                let _ = tokio::fs::remove_file(format!("/tmp/{}", value.id)).await;
-            })
-        })
-        .build()
-});
+/// User id from the X-User-Id header, `None` for anonymous requests.
+fn get_user_id(headers: &axum::http::HeaderMap) -> Option<i64> {
+    headers
+        .get("X-User-Id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+}
 
 async fn create_archive_task(
     headers: axum::http::HeaderMap,
@@ -47,35 +43,165 @@ async fn create_archive_task(
     // Derive user_id exclusively from X-User-Id header (authoritative source).
     // Never trust user_id from the JSON body — it could allow impersonation.
     // If header is absent → None (anonymous), avoiding dummy sentinel values.
-    data.user_id = headers
-        .get("X-User-Id")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.parse::<i64>().ok());
+    data.user_id = get_user_id(&headers);
 
     let key = get_key(data.clone());
+    let user_id = data.user_id;
+
+    let existing = match TASKS.get(&key).await {
+        Ok(Some(result)) => {
+            let rebuild = match result.status {
+                TaskStatus::Failed | TaskStatus::RateLimited => true,
+                TaskStatus::Complete => {
+                    data.force_rebuild || is_archive_stale(&result, &data).await
+                }
+                // The build lock keeps a running build from being duplicated.
+                _ if result.status.is_active() => {
+                    is_task_abandoned(&result, unix_time(), CONFIG.task_stall_timeout_secs)
+                }
+                _ => false,
+            };
 
-    let result = match TASK_RESULTS.get(&key).await {
-        Some(result) => {
-            if result.status == TaskStatus::Failed {
-                create_task(data).await
+            if rebuild {
+                None
             } else {
-                result
+                Some(result)
+            }
+        }
+        Ok(None) => None,
+        Err(err) => {
+            error!("Can't get task {}: {}", key, err);
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    };
+
+    let reused = existing.is_some();
+    let result = match existing {
+        Some(v) => v,
+        None => {
+            // Building needs TFCS, so don't start while the user is throttled.
+            if let Some(retry_after) = get_retry_after(user_id).await {
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(http::header::RETRY_AFTER, retry_after.to_string())],
+                )
+                    .into_response();
+            }
+
+            // API-only instances don't build archives, workers check
+            // their own storage.
+            if CONFIG.run_mode.builds_archives() && !has_free_space() {
+                return StatusCode::INSUFFICIENT_STORAGE.into_response();
+            }
+
+            if shutdown::is_shutting_down() {
+                return StatusCode::SERVICE_UNAVAILABLE.into_response();
+            }
+
+            match create_task(data).await {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("Can't create task {}: {}", key, err);
+                    return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                }
             }
         }
-        None => create_task(data).await,
     };
 
-    Json::<Task>(result).into_response()
+    track_requester(&key, user_id, reused).await;
+
+    Json::<Task>(result.for_user(user_id)).into_response()
 }
 
-async fn check_archive_task_status(Path(task_id): Path<String>) -> impl IntoResponse {
-    match TASK_RESULTS.get(&task_id).await {
-        Some(result) => Json::<Task>(result).into_response(),
-        None => StatusCode::NOT_FOUND.into_response(),
+async fn check_archive_task_status(
+    headers: axum::http::HeaderMap,
+    Path(task_id): Path<String>,
+) -> impl IntoResponse {
+    match TASKS.get(&task_id).await {
+        Ok(Some(result)) => Json::<Task>(result.for_user(get_user_id(&headers))).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!("Can't get task {}: {}", task_id, err);
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
     }
 }
 
-async fn auth(req: Request<axum::body::Body>, next: Next) -> Result<Response, StatusCode> {
+async fn invalidate_library_cache() -> impl IntoResponse {
+    match invalidate_all_caches().await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(err) => {
+            error!("Can't invalidate library cache: {}", err);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+async fn invalidate_library_object_cache(
+    Path((object_type, object_id)): Path<(ObjectType, u32)>,
+) -> impl IntoResponse {
+    match invalidate_cache(object_type, object_id).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(err) => {
+            error!("Can't invalidate library cache of {}: {}", object_id, err);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+async fn list_tasks(Query(query): Query<TaskListQuery>) -> impl IntoResponse {
+    match admin::list_tasks(&query).await {
+        Ok(tasks) => Json(tasks).into_response(),
+        Err(err) => {
+            error!("Can't list tasks: {}", err);
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+    }
+}
+
+fn admin_error_response(err: AdminError) -> Response {
+    match err {
+        AdminError::NotFound => StatusCode::NOT_FOUND.into_response(),
+        AdminError::Conflict(reason) => (StatusCode::CONFLICT, reason).into_response(),
+        AdminError::Store(err) => {
+            error!("Task store error: {}", err);
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+    }
+}
+
+async fn get_task_details(Path(task_id): Path<String>) -> impl IntoResponse {
+    match admin::get_task_details(&task_id).await {
+        Ok(details) => Json(details).into_response(),
+        Err(err) => admin_error_response(err),
+    }
+}
+
+async fn expire_task(Path(task_id): Path<String>) -> impl IntoResponse {
+    match admin::expire_task(&task_id).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => admin_error_response(err),
+    }
+}
+
+async fn rebuild_task(Path(task_id): Path<String>) -> impl IntoResponse {
+    match admin::rebuild_task(&task_id).await {
+        Ok(task) => Json::<Task>(task).into_response(),
+        Err(err) => admin_error_response(err),
+    }
+}
+
+async fn purge_failed_tasks() -> impl IntoResponse {
+    match admin::purge_failed_tasks().await {
+        Ok(removed) => Json(serde_json::json!({ "removed": removed })).into_response(),
+        Err(err) => {
+            error!("Can't purge failed tasks: {}", err);
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+    }
+}
+
+fn check_auth_header(req: &Request<axum::body::Body>, key: &str) -> Result<(), StatusCode> {
     let auth_header = req
         .headers()
         .get(http::header::AUTHORIZATION)
@@ -87,33 +213,88 @@ async fn auth(req: Request<axum::body::Body>, next: Next) -> Result<Response, St
         return Err(StatusCode::UNAUTHORIZED);
     };
 
-    if auth_header != CONFIG.api_key {
+    if auth_header != key {
         return Err(StatusCode::UNAUTHORIZED);
     }
 
+    Ok(())
+}
+
+async fn auth(req: Request<axum::body::Body>, next: Next) -> Result<Response, StatusCode> {
+    check_auth_header(&req, &CONFIG.api_key)?;
+
     Ok(next.run(req).await)
 }
 
-async fn download(Path(task_id): Path<String>) -> impl IntoResponse {
-    let task = match TASK_RESULTS.get(&task_id).await {
-        Some(result) => result,
-        None => return StatusCode::NOT_FOUND.into_response(),
-    };
+/// Admin routes use their own key, they are disabled if it isn't set.
+async fn admin_auth(req: Request<axum::body::Body>, next: Next) -> Result<Response, StatusCode> {
+    if CONFIG.admin_api_key.is_empty() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    check_auth_header(&req, &CONFIG.admin_api_key)?;
 
-    let file = match File::open(format!("/tmp/{}", task.id)).await {
-        Ok(v) => v,
-        Err(_) => return StatusCode::NOT_FOUND.into_response(),
+    Ok(next.run(req).await)
+}
+
+async fn download(Path(task_id): Path<String>) -> impl IntoResponse {
+    let task = match TASKS.get(&task_id).await {
+        Ok(Some(result)) => result,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!("Can't get task {}: {}", task_id, err);
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
     };
 
-    let stream = ReaderStream::new(file);
+    if CONFIG.download_mode == DownloadMode::Redirect {
+        match STORAGE
+            .presigned_url(&task.id, CONFIG.presigned_url_ttl_secs)
+            .await
+        {
+            Ok(Some(url)) => return Redirect::temporary(&url).into_response(),
+            Ok(None) => (),
+            // Still try to serve the archive through the service.
+            Err(err) => error!("Can't presign archive {}: {}", task.id, err),
+        }
+    }
 
-    Body::from_stream(stream).into_response()
+    match STORAGE.get(&task.id).await {
+        Ok(Some(stream)) => Body::from_stream(stream).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!("Can't read archive {}: {}", task.id, err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
 }
 
 async fn health_check() -> impl IntoResponse {
     StatusCode::OK
 }
 
+async fn readiness_check() -> impl IntoResponse {
+    let readiness = check_readiness().await;
+
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(readiness))
+}
+
+/// Router of the instances that only build archives.
+pub async fn get_worker_router() -> Router {
+    let (_, metric_handle) = PrometheusMetricLayer::pair();
+
+    Router::new()
+        .route("/metrics", get(|| async move { metric_handle.render() }))
+        .route("/health", get(health_check))
+        .route("/ready", get(readiness_check))
+}
+
 pub async fn get_router() -> Router {
     let (prometheus_layer, metric_handle) = PrometheusMetricLayer::pair();
 
@@ -124,6 +305,22 @@ pub async fn get_router() -> Router {
             get(check_archive_task_status),
         )
         .layer(middleware::from_fn(auth))
+        .layer(prometheus_layer.clone());
+
+    let admin_router = Router::new()
+        .route("/api/admin/library_cache", delete(invalidate_library_cache))
+        .route(
+            "/api/admin/library_cache/{object_type}/{object_id}",
+            delete(invalidate_library_object_cache),
+        )
+        .route("/api/admin/tasks", get(list_tasks))
+        .route("/api/admin/tasks/failed", delete(purge_failed_tasks))
+        .route(
+            "/api/admin/tasks/{task_id}",
+            get(get_task_details).delete(expire_task),
+        )
+        .route("/api/admin/tasks/{task_id}/rebuild", post(rebuild_task))
+        .layer(middleware::from_fn(admin_auth))
         .layer(prometheus_layer);
 
     let metric_router =
@@ -131,11 +328,13 @@ pub async fn get_router() -> Router {
 
     let public_router = Router::new()
         .route("/api/download/{task_id}", get(download))
-        .route("/health", get(health_check));
+        .route("/health", get(health_check))
+        .route("/ready", get(readiness_check));
 
     Router::new()
         .merge(public_router)
         .merge(app_router)
+        .merge(admin_router)
         .merge(metric_router)
         .layer(
             TraceLayer::new_for_http()